@@ -1,11 +1,7 @@
 mod common;
 
 use bevy::{
-    core_pipeline::{
-        bloom::Bloom,
-        experimental::taa::{TemporalAntiAliasPlugin, TemporalAntiAliasing},
-        tonemapping::Tonemapping,
-    },
+    core_pipeline::{experimental::taa::TemporalAntiAliasPlugin, tonemapping::Tonemapping},
     prelude::*,
 };
 use common::{FlyCam, FlyCamPlugin};
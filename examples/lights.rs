@@ -0,0 +1,94 @@
+mod common;
+
+use bevy::prelude::*;
+use common::{FlyCam, FlyCamPlugin};
+use path_tracing::{RayTracePlugin, RayTraceSettings};
+
+fn main() {
+    App::new()
+        .add_plugins((DefaultPlugins, RayTracePlugin, FlyCamPlugin))
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let (samples, bounces) = common::get_settings();
+
+    commands.spawn((
+        Camera3d::default(),
+        Camera {
+            hdr: true,
+            clear_color: ClearColorConfig::Custom(Color::BLACK),
+            ..default()
+        },
+        Transform::from_xyz(4.0, 3.0, 6.0).looking_at(Vec3::ZERO, Vec3::Y),
+        FlyCam {
+            speed: 6.0,
+            sensitivity: 0.1,
+            ..default()
+        },
+        RayTraceSettings {
+            bounces,
+            samples,
+            sky_color: Color::BLACK.into(),
+        },
+        Msaa::Off,
+    ));
+
+    let white = materials.add(StandardMaterial {
+        base_color: Color::WHITE,
+        perceptual_roughness: 1.0,
+        ..default()
+    });
+
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(5.0)))),
+        MeshMaterial3d(white.clone()),
+    ));
+    for (x, color) in [
+        (-2.0, Color::linear_rgb(1.0, 0.1, 0.1)),
+        (0.0, Color::WHITE),
+        (2.0, Color::linear_rgb(0.1, 0.1, 1.0)),
+    ] {
+        commands.spawn((
+            Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
+            MeshMaterial3d(materials.add(StandardMaterial {
+                base_color: color,
+                perceptual_roughness: 1.0,
+                ..default()
+            })),
+            Transform::from_xyz(x, 0.5, 0.0),
+        ));
+    }
+
+    commands.spawn((
+        PointLight {
+            color: Color::linear_rgb(1.0, 0.8, 0.6),
+            radius: 0.1,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(-2.0, 2.5, 2.0),
+    ));
+    commands.spawn((
+        SpotLight {
+            color: Color::linear_rgb(0.6, 0.8, 1.0),
+            intensity: 4_000_000.0,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(2.0, 4.0, 2.0).looking_at(Vec3::new(2.0, 0.0, 0.0), Vec3::Y),
+    ));
+    commands.spawn((
+        DirectionalLight {
+            illuminance: light_consts::lux::OVERCAST_DAY,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(1.0, 2.0, 1.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+}
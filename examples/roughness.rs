@@ -0,0 +1,109 @@
+mod common;
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+use common::{FlyCam, FlyCamPlugin};
+use path_tracing::{RayTracePlugin, RayTraceSettings};
+
+const SIZE: u32 = 64;
+
+fn main() {
+    App::new()
+        .add_plugins((DefaultPlugins, RayTracePlugin, FlyCamPlugin))
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(
+    mut images: ResMut<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let (samples, bounces) = common::get_settings();
+
+    commands.spawn((
+        Camera3d::default(),
+        Camera {
+            hdr: true,
+            clear_color: ClearColorConfig::Custom(Color::linear_rgb(0.1, 0.2, 0.4)),
+            ..default()
+        },
+        Transform::from_xyz(0.0, 3.0, 4.0).looking_at(Vec3::ZERO, Vec3::Y),
+        FlyCam {
+            speed: 6.0,
+            sensitivity: 0.1,
+            ..default()
+        },
+        RayTraceSettings {
+            bounces,
+            samples,
+            sky_color: Color::linear_rgb(0.1, 0.2, 0.4).into(),
+        },
+        Msaa::Off,
+    ));
+
+    // Roughness from 0 to 1 along u in green, dielectric top half and metallic bottom half in blue
+    let mut data = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let roughness = (x * 255 / (SIZE - 1)) as u8;
+            let metallic = if y < SIZE / 2 { 0 } else { 255 };
+            data.extend_from_slice(&[0, roughness, metallic, 255]);
+        }
+    }
+    let metallic_roughness = images.add(Image::new(
+        Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::default(),
+    ));
+
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::new(Vec3::Y, Vec2::new(3.0, 2.0)))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::linear_rgb(0.9, 0.9, 0.9),
+            perceptual_roughness: 1.0,
+            metallic: 1.0,
+            metallic_roughness_texture: Some(metallic_roughness),
+            ..default()
+        })),
+    ));
+
+    let cube = meshes.add(Cuboid::new(0.5, 0.5, 0.5));
+    for (x, color) in [
+        (-2.0, Color::linear_rgb(1.0, 0.1, 0.1)),
+        (0.0, Color::linear_rgb(0.1, 1.0, 0.1)),
+        (2.0, Color::linear_rgb(0.1, 0.1, 1.0)),
+    ] {
+        commands.spawn((
+            Mesh3d(cube.clone()),
+            MeshMaterial3d(materials.add(StandardMaterial {
+                base_color: color,
+                perceptual_roughness: 1.0,
+                ..default()
+            })),
+            Transform::from_xyz(x, 0.25, -1.0),
+        ));
+    }
+
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::new(Vec3::NEG_Y, Vec2::new(3.0, 0.5)))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::BLACK,
+            emissive: LinearRgba::rgb(4.0, 4.0, 4.0),
+            ..default()
+        })),
+        Transform::from_xyz(0.0, 3.0, -1.0),
+    ));
+}
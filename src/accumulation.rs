@@ -0,0 +1,113 @@
+use std::sync::atomic::Ordering;
+
+use bevy::{
+    core::FrameCount,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::{CachedTexture, TextureCache},
+        view::ExtractedView,
+    },
+};
+
+use crate::data::{RayTraceAccumulation, RayTraceMeta, RayTraceSettings};
+
+/// Running sum of the traced radiance in rgb and the number of samples in alpha
+pub const ACCUMULATION_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+
+#[derive(Component)]
+pub struct RayTraceAccumulationTextures {
+    pub write: CachedTexture,
+    pub read: CachedTexture,
+    /// Whether `read` has to be cleared before it is used
+    pub reset: bool,
+}
+
+/// What the accumulated samples of a view were traced with
+#[derive(Component, Clone, Copy, PartialEq)]
+pub struct AccumulationState {
+    world_from_view: Mat4,
+    clip_from_view: Mat4,
+    size: UVec2,
+    settings: RayTraceSettings,
+    generation: u64,
+    samples: u32,
+}
+
+#[allow(clippy::type_complexity)]
+pub fn prepare_accumulation_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    frame_count: Res<FrameCount>,
+    raytrace_meta: Res<RayTraceMeta>,
+    views: Query<(
+        Entity,
+        &ExtractedCamera,
+        &ExtractedView,
+        &RayTraceSettings,
+        &RayTraceAccumulation,
+        Option<&AccumulationState>,
+    )>,
+) {
+    for (entity, camera, view, settings, accumulation, previous) in &views {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+
+        let mut state = AccumulationState {
+            world_from_view: view.world_from_view.compute_matrix(),
+            clip_from_view: view.clip_from_view,
+            size,
+            settings: *settings,
+            generation: raytrace_meta.generation,
+            samples: 0,
+        };
+        let reset = previous.is_none_or(|previous| {
+            AccumulationState {
+                samples: 0,
+                ..*previous
+            } != state
+        });
+        state.samples = match previous {
+            Some(previous) if !reset => previous.samples + settings.samples,
+            _ => settings.samples,
+        };
+        accumulation.samples.store(state.samples, Ordering::Relaxed);
+
+        let mut texture_descriptor = TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                depth_or_array_layers: 1,
+                width: size.x,
+                height: size.y,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: ACCUMULATION_TEXTURE_FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        };
+
+        texture_descriptor.label = Some("ray_trace_accumulation_1_texture");
+        let accumulation_1_texture = texture_cache.get(&render_device, texture_descriptor.clone());
+
+        texture_descriptor.label = Some("ray_trace_accumulation_2_texture");
+        let accumulation_2_texture = texture_cache.get(&render_device, texture_descriptor);
+
+        let (write, read) = if frame_count.0.is_multiple_of(2) {
+            (accumulation_1_texture, accumulation_2_texture)
+        } else {
+            (accumulation_2_texture, accumulation_1_texture)
+        };
+
+        commands
+            .entity(entity)
+            .insert((RayTraceAccumulationTextures { write, read, reset }, state));
+    }
+}
@@ -0,0 +1,132 @@
+use std::ops::Range;
+
+/// First fit allocator for ranges of a buffer, freed ranges are merged with their neighbours
+#[derive(Default)]
+pub struct RangeAllocator {
+    /// Sorted by start and never adjacent
+    free: Vec<Range<u32>>,
+    /// End of the highest range ever allocated
+    pub len: u32,
+}
+
+impl RangeAllocator {
+    pub fn alloc(&mut self, size: u32) -> Range<u32> {
+        if let Some(i) = self.free.iter().position(|r| r.end - r.start >= size) {
+            let range = &mut self.free[i];
+            let start = range.start;
+            range.start += size;
+            if range.start == range.end {
+                self.free.remove(i);
+            }
+            return start..start + size;
+        }
+
+        // Grow into the free range at the end if there is one
+        let start = match self.free.last() {
+            Some(last) if last.end == self.len => self.free.pop().unwrap().start,
+            _ => self.len,
+        };
+        self.len = start + size;
+        start..self.len
+    }
+
+    pub fn free(&mut self, range: Range<u32>) {
+        if range.start == range.end {
+            return;
+        }
+
+        let i = self.free.partition_point(|r| r.start < range.start);
+        let merge_prev = i > 0 && self.free[i - 1].end == range.start;
+        let merge_next = i < self.free.len() && self.free[i].start == range.end;
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.free[i - 1].end = self.free[i].end;
+                self.free.remove(i);
+            }
+            (true, false) => self.free[i - 1].end = range.end,
+            (false, true) => self.free[i].start = range.start,
+            (false, false) => self.free.insert(i, range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_grows() {
+        let mut allocator = RangeAllocator::default();
+        assert_eq!(allocator.alloc(4), 0..4);
+        assert_eq!(allocator.alloc(6), 4..10);
+        assert_eq!(allocator.len, 10);
+        assert!(allocator.free.is_empty());
+    }
+
+    #[test]
+    fn freed_ranges_are_reused_first_fit() {
+        let mut allocator = RangeAllocator::default();
+        let a = allocator.alloc(4);
+        allocator.alloc(4);
+        allocator.free(a);
+
+        assert_eq!(allocator.alloc(3), 0..3);
+        // The one left over is too small
+        assert_eq!(allocator.alloc(2), 8..10);
+        assert_eq!(allocator.alloc(1), 3..4);
+        assert!(allocator.free.is_empty());
+    }
+
+    #[test]
+    fn free_merges_both_sides() {
+        let mut allocator = RangeAllocator::default();
+        let ranges: Vec<_> = (0..5).map(|_| allocator.alloc(2)).collect();
+
+        allocator.free(ranges[1].clone());
+        allocator.free(ranges[3].clone());
+        assert_eq!(allocator.free, [2..4, 6..8]);
+
+        // Merges with the previous range, then the next one, then both
+        allocator.free(ranges[0].clone());
+        assert_eq!(allocator.free, [0..4, 6..8]);
+        allocator.free(ranges[4].clone());
+        assert_eq!(allocator.free, [0..4, 6..10]);
+        allocator.free(ranges[2].clone());
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.free[0], 0..10);
+
+        assert_eq!(allocator.alloc(10), 0..10);
+        assert_eq!(allocator.len, 10);
+    }
+
+    #[test]
+    fn alloc_extends_free_range_at_the_end() {
+        let mut allocator = RangeAllocator::default();
+        allocator.alloc(4);
+        let b = allocator.alloc(4);
+        allocator.free(b);
+
+        assert_eq!(allocator.alloc(6), 4..10);
+        assert_eq!(allocator.len, 10);
+        assert!(allocator.free.is_empty());
+    }
+
+    #[test]
+    fn zero_size_ranges() {
+        let mut allocator = RangeAllocator::default();
+        assert!(allocator.alloc(0).is_empty());
+        assert_eq!(allocator.len, 0);
+
+        let a = allocator.alloc(4);
+        allocator.alloc(4);
+        allocator.free(4..4);
+        assert!(allocator.free.is_empty());
+
+        // Empty ranges don't take space from the free list
+        allocator.free(a);
+        assert!(allocator.alloc(0).is_empty());
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.free[0], 0..4);
+        assert_eq!(allocator.len, 8);
+    }
+}
@@ -0,0 +1,295 @@
+use std::f32::consts::PI;
+
+use bevy::math::{Mat3, Vec2, Vec3};
+
+// Mirror of `bsdf.wgsl`, changes have to be made to both
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Surface {
+    pub albedo: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub reflectance: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BsdfSample {
+    pub dir: Vec3,
+    /// BSDF times the cosine term divided by `pdf`
+    pub weight: Vec3,
+    /// Solid angle density of `dir`
+    pub pdf: f32,
+}
+
+/// Lambertian diffuse plus a GGX specular lobe, returns the BSDF times the cosine term
+pub fn evaluate(surface: &Surface, n: Vec3, v: Vec3, l: Vec3) -> Vec3 {
+    let n_dot_v = n.dot(v);
+    let n_dot_l = n.dot(l);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let h = (v + l).normalize();
+    let n_dot_h = n.dot(h).max(0.0);
+    let v_dot_h = v.dot(h).max(0.0);
+    let a2 = alpha(surface.roughness).powi(2);
+
+    let f0 = f0(surface);
+    let f = fresnel_schlick(f0, v_dot_h);
+    let d = d_ggx(n_dot_h, a2);
+    let g = 1.0 / (1.0 + smith_lambda(n_dot_v, a2) + smith_lambda(n_dot_l, a2));
+
+    // Diffuse only receives what is transmitted on the way in and out
+    let transmitted = (1.0 - fresnel_schlick(f0, n_dot_v)) * (1.0 - fresnel_schlick(f0, n_dot_l));
+
+    let specular = f * d * g / (4.0 * n_dot_v * n_dot_l);
+    let diffuse = transmitted * (1.0 - surface.metallic) * surface.albedo / PI;
+    (diffuse + specular) * n_dot_l
+}
+
+/// Solid angle density [`sample`] picks `l` with
+pub fn pdf(surface: &Surface, n: Vec3, v: Vec3, l: Vec3) -> f32 {
+    let n_dot_v = n.dot(v);
+    let n_dot_l = n.dot(l);
+    if n_dot_v <= 0.0 || n_dot_l <= 0.0 {
+        return 0.0;
+    }
+
+    let h = (v + l).normalize();
+    let n_dot_h = n.dot(h).max(0.0);
+    let a2 = alpha(surface.roughness).powi(2);
+
+    // Reflected visible normals, D_v(H) / (4 VdotH)
+    let g1 = 1.0 / (1.0 + smith_lambda(n_dot_v, a2));
+    let specular = g1 * d_ggx(n_dot_h, a2) / (4.0 * n_dot_v);
+    let diffuse = n_dot_l / PI;
+    diffuse + (specular - diffuse) * specular_probability(surface, n_dot_v)
+}
+
+/// `rng.x` picks the lobe and `rng.yz` the direction within it
+pub fn sample(surface: &Surface, n: Vec3, v: Vec3, rng: Vec3) -> BsdfSample {
+    let tbn = hugues_moller(n);
+    let v_local = tbn.transpose() * v;
+    if v_local.z <= 0.0 {
+        return BsdfSample {
+            dir: n,
+            weight: Vec3::ZERO,
+            pdf: 0.0,
+        };
+    }
+
+    let l_local = if rng.x < specular_probability(surface, v_local.z) {
+        let h = sample_vndf(v_local, alpha(surface.roughness), Vec2::new(rng.y, rng.z));
+        (-v_local).reflect(h)
+    } else {
+        cosine_hemisphere(Vec2::new(rng.y, rng.z))
+    };
+
+    let l = (tbn * l_local).normalize();
+    let density = pdf(surface, n, v, l);
+    if density <= 0.0 {
+        return BsdfSample {
+            dir: l,
+            weight: Vec3::ZERO,
+            pdf: 0.0,
+        };
+    }
+
+    BsdfSample {
+        dir: l,
+        weight: evaluate(surface, n, v, l) / density,
+        pdf: density,
+    }
+}
+
+// Same as Bevy's `calculate_F0`
+fn f0(surface: &Surface) -> Vec3 {
+    let dielectric = 0.16 * surface.reflectance * surface.reflectance * (1.0 - surface.metallic);
+    dielectric + surface.albedo * surface.metallic
+}
+
+// Same clamp as Bevy's `perceptualRoughnessToRoughness`
+fn alpha(perceptual_roughness: f32) -> f32 {
+    perceptual_roughness.clamp(0.089, 1.0).powi(2)
+}
+
+fn fresnel_schlick(f0: Vec3, cos_theta: f32) -> Vec3 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta).powi(5)
+}
+
+fn d_ggx(n_dot_h: f32, a2: f32) -> f32 {
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+fn smith_lambda(cos_theta: f32, a2: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    ((1.0 + a2 * (1.0 - cos2) / cos2).sqrt() - 1.0) * 0.5
+}
+
+// Chance of sampling the specular lobe, its Fresnel weight against the diffuse one
+fn specular_probability(surface: &Surface, n_dot_v: f32) -> f32 {
+    let specular = luminance(fresnel_schlick(f0(surface), n_dot_v));
+    let diffuse = luminance(surface.albedo) * (1.0 - surface.metallic) * (1.0 - specular);
+    (specular / (specular + diffuse).max(0.0001)).clamp(0.0, 1.0)
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// Visible normal in tangent space, https://arxiv.org/abs/2306.05044
+fn sample_vndf(v: Vec3, alpha: f32, rng: Vec2) -> Vec3 {
+    let vh = Vec3::new(alpha * v.x, alpha * v.y, v.z).normalize();
+
+    let phi = 2.0 * PI * rng.x;
+    let z = (1.0 - rng.y) * (1.0 + vh.z) - vh.z;
+    let sin_theta = (1.0 - z * z).clamp(0.0, 1.0).sqrt();
+    let hh = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z) + vh;
+
+    Vec3::new(alpha * hh.x, alpha * hh.y, hh.z.max(0.0)).normalize()
+}
+
+fn cosine_hemisphere(rng: Vec2) -> Vec3 {
+    let phi = 2.0 * PI * rng.x;
+    let sin_theta = rng.y.sqrt();
+    let cos_theta = (1.0 - rng.y).sqrt();
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+fn hugues_moller(n: Vec3) -> Mat3 {
+    let a = n.abs();
+    let t = if a.x <= a.y && a.x <= a.z {
+        Vec3::new(0.0, -n.z, n.y)
+    } else if a.y <= a.x && a.y <= a.z {
+        Vec3::new(-n.z, 0.0, n.x)
+    } else {
+        Vec3::new(-n.y, n.x, 0.0)
+    }
+    .normalize();
+
+    let b = n.cross(t).normalize();
+    Mat3::from_cols(t, b, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rng;
+
+    fn surfaces(roughness: &[f32]) -> impl Iterator<Item = Surface> + '_ {
+        roughness.iter().flat_map(|&roughness| {
+            [0.0, 0.5, 1.0].map(|metallic| Surface {
+                albedo: Vec3::ONE,
+                metallic,
+                roughness,
+                reflectance: 0.5,
+            })
+        })
+    }
+
+    fn view(cos_theta: f32) -> Vec3 {
+        Vec3::new((1.0 - cos_theta * cos_theta).sqrt(), 0.0, cos_theta)
+    }
+
+    #[test]
+    fn white_furnace() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for surface in surfaces(&[0.0, 0.1, 0.3, 0.6, 1.0]) {
+            for cos_theta in [0.1, 0.5, 1.0] {
+                let v = view(cos_theta);
+                let samples = 50_000;
+                let albedo = (0..samples)
+                    .map(|_| sample(&surface, Vec3::Z, v, rng.vec3()).weight)
+                    .sum::<Vec3>()
+                    / samples as f32;
+                assert!(
+                    albedo.max_element() <= 1.01,
+                    "{surface:?} at cos {cos_theta} reflects {albedo}"
+                );
+
+                // Single scattering loses what bounces between microfacets, so rough lobes
+                // and grazing views only get a floor where that loss is known to be small
+                let white = surface.metallic == 0.0;
+                let metal = surface.metallic == 1.0;
+                let floor = if white && surface.roughness <= 0.1 {
+                    0.9
+                } else if white && cos_theta >= 0.5 {
+                    0.85
+                } else if metal && surface.roughness <= 0.6 && cos_theta >= 0.5 {
+                    0.75
+                } else {
+                    0.0
+                };
+                assert!(
+                    albedo.min_element() >= floor,
+                    "{surface:?} at cos {cos_theta} only reflects {albedo}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reciprocity() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for surface in surfaces(&[0.1, 0.3, 0.6, 1.0]) {
+            for _ in 0..1000 {
+                let a = rng.signed_vec3().with_z(rng.f32()).normalize();
+                let b = rng.signed_vec3().with_z(rng.f32()).normalize();
+
+                // `evaluate` includes the cosine of the incoming direction
+                let ab = evaluate(&surface, Vec3::Z, a, b) / b.z;
+                let ba = evaluate(&surface, Vec3::Z, b, a) / a.z;
+                assert!(
+                    (ab - ba).abs().max_element() <= 1e-4 * ab.max_element().max(1.0),
+                    "{surface:?} {a} {b}: {ab} != {ba}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sampled_pdf_matches_pdf() {
+        let mut rng = Rng(0x853c49e6748fea9b);
+        for surface in surfaces(&[0.3, 0.6, 1.0]) {
+            for cos_theta in [0.3, 0.7, 1.0] {
+                let v = view(cos_theta);
+
+                // Mean direction of the samples, those reflected below the surface are lost
+                let samples = 100_000;
+                let mut sampled = Vec3::ZERO;
+                for _ in 0..samples {
+                    let s = sample(&surface, Vec3::Z, v, rng.vec3());
+                    if s.pdf > 0.0 {
+                        assert!((s.pdf - pdf(&surface, Vec3::Z, v, s.dir)).abs() <= 1e-4 * s.pdf);
+                        sampled += s.dir;
+                    }
+                }
+                sampled /= samples as f32;
+
+                // Mean direction under `pdf`, integrated over the hemisphere
+                let steps = 512;
+                let d_omega = 2.0 * PI / (steps * steps) as f32;
+                let mut total = 0.0;
+                let mut expected = Vec3::ZERO;
+                for i in 0..steps {
+                    let z = (i as f32 + 0.5) / steps as f32;
+                    let r = (1.0 - z * z).sqrt();
+                    for j in 0..steps {
+                        let phi = 2.0 * PI * (j as f32 + 0.5) / steps as f32;
+                        let l = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                        let p = pdf(&surface, Vec3::Z, v, l) * d_omega;
+                        total += p;
+                        expected += l * p;
+                    }
+                }
+
+                assert!(total < 1.01, "{surface:?} integrates to {total}");
+                assert!(
+                    (sampled - expected).abs().max_element() < 0.01,
+                    "{surface:?} at cos {cos_theta}: {sampled} != {expected}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+#define_import_path path_tracing::bsdf
+
+#import bevy_render::maths::PI
+
+// Mirrored by `bsdf.rs`, changes have to be made to both
+
+struct Surface {
+    albedo: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    reflectance: f32,
+}
+
+struct BsdfSample {
+    dir: vec3<f32>,
+    // BSDF times the cosine term divided by `pdf`
+    weight: vec3<f32>,
+    // Solid angle density of `dir`
+    pdf: f32,
+}
+
+// Lambertian diffuse plus a GGX specular lobe, returns the BSDF times the cosine term
+fn evaluate(surface: Surface, N: vec3<f32>, V: vec3<f32>, L: vec3<f32>) -> vec3<f32> {
+    let NdotV = dot(N, V);
+    let NdotL = dot(N, L);
+    if NdotV <= 0.0 || NdotL <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+
+    let H = normalize(V + L);
+    let NdotH = max(dot(N, H), 0.0);
+    let VdotH = max(dot(V, H), 0.0);
+    let a2 = pow(alpha(surface.roughness), 2.0);
+
+    let F0 = f0(surface);
+    let F = fresnel_schlick(F0, VdotH);
+    let D = d_ggx(NdotH, a2);
+    let G = 1.0 / (1.0 + smith_lambda(NdotV, a2) + smith_lambda(NdotL, a2));
+
+    // Diffuse only receives what is transmitted on the way in and out
+    let transmitted = (1.0 - fresnel_schlick(F0, NdotV)) * (1.0 - fresnel_schlick(F0, NdotL));
+
+    let specular = F * D * G / (4.0 * NdotV * NdotL);
+    let diffuse = transmitted * (1.0 - surface.metallic) * surface.albedo / PI;
+    return (diffuse + specular) * NdotL;
+}
+
+// Solid angle density `sample` picks `L` with
+fn pdf(surface: Surface, N: vec3<f32>, V: vec3<f32>, L: vec3<f32>) -> f32 {
+    let NdotV = dot(N, V);
+    let NdotL = dot(N, L);
+    if NdotV <= 0.0 || NdotL <= 0.0 {
+        return 0.0;
+    }
+
+    let H = normalize(V + L);
+    let NdotH = max(dot(N, H), 0.0);
+    let a2 = pow(alpha(surface.roughness), 2.0);
+
+    // Reflected visible normals, D_v(H) / (4 VdotH)
+    let G1 = 1.0 / (1.0 + smith_lambda(NdotV, a2));
+    let specular = G1 * d_ggx(NdotH, a2) / (4.0 * NdotV);
+    let diffuse = NdotL / PI;
+    return mix(diffuse, specular, specular_probability(surface, NdotV));
+}
+
+// `rng.x` picks the lobe and `rng.yz` the direction within it
+fn sample(surface: Surface, N: vec3<f32>, V: vec3<f32>, rng: vec3<f32>) -> BsdfSample {
+    let tbn = hugues_moller(N);
+    let v = V * tbn;
+    if v.z <= 0.0 {
+        return BsdfSample(N, vec3<f32>(0.0), 0.0);
+    }
+
+    var l: vec3<f32>;
+    if rng.x < specular_probability(surface, v.z) {
+        let h = sample_vndf(v, alpha(surface.roughness), rng.yz);
+        l = reflect(-v, h);
+    } else {
+        l = cosine_hemisphere(rng.yz);
+    }
+
+    let L = normalize(tbn * l);
+    let density = pdf(surface, N, V, L);
+    if density <= 0.0 {
+        return BsdfSample(L, vec3<f32>(0.0), 0.0);
+    }
+
+    return BsdfSample(L, evaluate(surface, N, V, L) / density, density);
+}
+
+// ---- Helper ----
+
+// Same as Bevy's `calculate_F0`
+fn f0(surface: Surface) -> vec3<f32> {
+    let dielectric = 0.16 * surface.reflectance * surface.reflectance * (1.0 - surface.metallic);
+    return dielectric + surface.albedo * surface.metallic;
+}
+
+// Same clamp as Bevy's `perceptualRoughnessToRoughness`
+fn alpha(perceptual_roughness: f32) -> f32 {
+    let r = clamp(perceptual_roughness, 0.089, 1.0);
+    return r * r;
+}
+
+fn fresnel_schlick(f0: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+fn d_ggx(NdotH: f32, a2: f32) -> f32 {
+    let d = NdotH * NdotH * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn smith_lambda(cos_theta: f32, a2: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    return (sqrt(1.0 + a2 * (1.0 - cos2) / cos2) - 1.0) * 0.5;
+}
+
+// Chance of sampling the specular lobe, its Fresnel weight against the diffuse one
+fn specular_probability(surface: Surface, NdotV: f32) -> f32 {
+    let specular = luminance(fresnel_schlick(f0(surface), NdotV));
+    let diffuse = luminance(surface.albedo) * (1.0 - surface.metallic) * (1.0 - specular);
+    return clamp(specular / max(specular + diffuse, 0.0001), 0.0, 1.0);
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Visible normal in tangent space, https://arxiv.org/abs/2306.05044
+fn sample_vndf(v: vec3<f32>, alpha: f32, rng: vec2<f32>) -> vec3<f32> {
+    let vh = normalize(vec3<f32>(alpha * v.x, alpha * v.y, v.z));
+
+    let phi = 2.0 * PI * rng.x;
+    let z = (1.0 - rng.y) * (1.0 + vh.z) - vh.z;
+    let sin_theta = sqrt(clamp(1.0 - z * z, 0.0, 1.0));
+    let hh = vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), z) + vh;
+
+    return normalize(vec3<f32>(alpha * hh.x, alpha * hh.y, max(hh.z, 0.0)));
+}
+
+fn cosine_hemisphere(rng: vec2<f32>) -> vec3<f32> {
+    let phi = 2.0 * PI * rng.x;
+    let sin_theta = sqrt(rng.y);
+    let cos_theta = sqrt(1.0 - rng.y);
+    return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+fn hugues_moller(n: vec3<f32>) -> mat3x3<f32> {
+    let a = abs(n);
+    var t = vec3<f32>(0);
+    if a.x <= a.y && a.x <= a.z {
+        t = vec3<f32>(0, -n.z, n.y);
+    } else if a.y <= a.x && a.y <= a.z {
+        t = vec3<f32>(-n.z, 0, n.x);
+    } else {
+        t = vec3<f32>(-n.y, n.x, 0);
+    }
+    t = normalize(t);
+
+    let b = normalize(cross(n, t));
+    return mat3x3<f32>(t, b, n);
+}
@@ -0,0 +1,483 @@
+use bevy::math::{BVec3, Mat4, Vec3};
+
+use crate::data::{BvhNode, GpuVertex};
+
+/// Maximum depth of a built hierarchy, this must match `BVH_STACK_SIZE` in `query.wgsl`
+pub const MAX_DEPTH: usize = 32;
+
+const BINS: usize = 16;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECT_COST: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl Bounds {
+    pub const EMPTY: Self = Self {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points.iter().fold(Self::EMPTY, |b, p| b.grow(*p))
+    }
+
+    pub fn grow(self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    /// Bounds of the box after transforming its corners by `matrix`
+    pub fn transformed(&self, matrix: &Mat4) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+
+        (0..8).fold(Self::EMPTY, |b, i| {
+            let corner = Vec3::select(
+                BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
+                self.max,
+                self.min,
+            );
+            b.grow(matrix.transform_point3(corner))
+        })
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+}
+
+/// A flattened hierarchy and the order its leaves reference the input primitives in.
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub primitives: Vec<u32>,
+}
+
+/// Builds a hierarchy over `bounds` using the binned surface area heuristic.
+///
+/// Nodes are stored depth first with both children of a node next to each other,
+/// so a child always has a larger index than its parent.
+pub fn build(bounds: &[Bounds]) -> Bvh {
+    let mut primitives: Vec<u32> = (0..bounds.len() as u32).collect();
+    let centroids: Vec<Vec3> = bounds.iter().map(Bounds::centroid).collect();
+
+    let mut nodes = vec![BvhNode {
+        aabb_min: Vec3::INFINITY,
+        first: 0,
+        aabb_max: Vec3::NEG_INFINITY,
+        count: bounds.len() as u32,
+    }];
+    if bounds.is_empty() {
+        return Bvh { nodes, primitives };
+    }
+
+    let mut stack = vec![(0usize, 1usize)];
+    while let Some((node_index, depth)) = stack.pop() {
+        let first = nodes[node_index].first as usize;
+        let count = nodes[node_index].count as usize;
+        let node_primitives = &mut primitives[first..first + count];
+
+        let node_bounds = node_primitives
+            .iter()
+            .fold(Bounds::EMPTY, |b, &p| b.union(bounds[p as usize]));
+        nodes[node_index].aabb_min = node_bounds.min;
+        nodes[node_index].aabb_max = node_bounds.max;
+
+        if count <= 1 || depth >= MAX_DEPTH {
+            continue;
+        }
+
+        let Some(split) = find_split(node_primitives, bounds, &centroids, node_bounds) else {
+            continue;
+        };
+
+        // Partition
+        let mut left_count = 0;
+        for i in 0..count {
+            if split.contains(centroids[node_primitives[i] as usize]) {
+                node_primitives.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+        if left_count == 0 || left_count == count {
+            continue;
+        }
+
+        let left = nodes.len();
+        nodes.push(BvhNode {
+            first: first as u32,
+            count: left_count as u32,
+            ..Default::default()
+        });
+        nodes.push(BvhNode {
+            first: (first + left_count) as u32,
+            count: (count - left_count) as u32,
+            ..Default::default()
+        });
+        nodes[node_index].first = left as u32;
+        nodes[node_index].count = 0;
+
+        stack.push((left, depth + 1));
+        stack.push((left + 1, depth + 1));
+    }
+
+    Bvh { nodes, primitives }
+}
+
+/// Recomputes the bounds of every node from `bounds` without changing the topology.
+pub fn refit(nodes: &mut [BvhNode], primitives: &[u32], bounds: &[Bounds]) {
+    for i in (0..nodes.len()).rev() {
+        let node = nodes[i];
+        let b = if node.count > 0 {
+            let first = node.first as usize;
+            primitives[first..first + node.count as usize]
+                .iter()
+                .fold(Bounds::EMPTY, |b, &p| b.union(bounds[p as usize]))
+        } else if i == 0 && primitives.is_empty() {
+            Bounds::EMPTY
+        } else {
+            let left = nodes[node.first as usize];
+            let right = nodes[node.first as usize + 1];
+            Bounds {
+                min: left.aabb_min.min(right.aabb_min),
+                max: left.aabb_max.max(right.aabb_max),
+            }
+        };
+
+        nodes[i].aabb_min = b.min;
+        nodes[i].aabb_max = b.max;
+    }
+}
+
+/// Number of levels of `nodes`, counting the root and the leaves.
+pub fn depth(nodes: &[BvhNode]) -> u32 {
+    // Children come after their parent, so walking backwards sees them first
+    let mut depths = vec![1; nodes.len()];
+    for i in (0..nodes.len()).rev() {
+        let node = nodes[i];
+        if node.count == 0 && node.first != 0 {
+            let first = node.first as usize;
+            depths[i] = 1 + depths[first].max(depths[first + 1]);
+        }
+    }
+    depths.first().copied().unwrap_or(0)
+}
+
+/// Builds a hierarchy over the triangles of a mesh, reordering `indices` so every leaf
+/// references a contiguous run of triangles.
+pub fn build_mesh(vertices: &[GpuVertex], indices: &mut Vec<u32>) -> Vec<BvhNode> {
+    let bounds: Vec<Bounds> = indices
+        .chunks_exact(3)
+        .map(|tri| {
+            Bounds::from_points(&[
+                vertices[tri[0] as usize].position,
+                vertices[tri[1] as usize].position,
+                vertices[tri[2] as usize].position,
+            ])
+        })
+        .collect();
+
+    let bvh = build(&bounds);
+    *indices = bvh
+        .primitives
+        .iter()
+        .flat_map(|&tri| {
+            let i = tri as usize * 3;
+            [indices[i], indices[i + 1], indices[i + 2]]
+        })
+        .collect();
+
+    bvh.nodes
+}
+
+#[derive(Clone, Copy)]
+struct Split {
+    axis: usize,
+    position: f32,
+}
+
+impl Split {
+    fn contains(&self, centroid: Vec3) -> bool {
+        centroid[self.axis] < self.position
+    }
+}
+
+fn find_split(
+    primitives: &[u32],
+    bounds: &[Bounds],
+    centroids: &[Vec3],
+    node_bounds: Bounds,
+) -> Option<Split> {
+    let centroid_bounds = primitives
+        .iter()
+        .fold(Bounds::EMPTY, |b, &p| b.grow(centroids[p as usize]));
+
+    let leaf_cost = primitives.len() as f32 * INTERSECT_COST;
+    let parent_area = node_bounds.surface_area();
+    let mut best: Option<(Split, f32)> = None;
+
+    for axis in [0, 1, 2] {
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        if extent <= f32::EPSILON {
+            continue;
+        }
+
+        // Bin
+        let scale = BINS as f32 / extent;
+        let mut bins = [(Bounds::EMPTY, 0u32); BINS];
+        for &p in primitives {
+            let b = (((centroids[p as usize][axis] - min) * scale) as usize).min(BINS - 1);
+            bins[b].0 = bins[b].0.union(bounds[p as usize]);
+            bins[b].1 += 1;
+        }
+
+        // Sweep
+        let mut left_area = [0.0; BINS - 1];
+        let mut left_count = [0; BINS - 1];
+        let mut acc = (Bounds::EMPTY, 0);
+        for i in 0..BINS - 1 {
+            acc = (acc.0.union(bins[i].0), acc.1 + bins[i].1);
+            left_area[i] = acc.0.surface_area();
+            left_count[i] = acc.1;
+        }
+
+        let mut acc = (Bounds::EMPTY, 0);
+        for i in (1..BINS).rev() {
+            acc = (acc.0.union(bins[i].0), acc.1 + bins[i].1);
+            let (l_count, r_count) = (left_count[i - 1], acc.1);
+            if l_count == 0 || r_count == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + INTERSECT_COST
+                    * (left_area[i - 1] * l_count as f32 + acc.0.surface_area() * r_count as f32)
+                    / parent_area.max(f32::EPSILON);
+            if best.is_none_or(|(_, c)| cost < c) {
+                let split = Split {
+                    axis,
+                    position: min + i as f32 / scale,
+                };
+                best = Some((split, cost));
+            }
+        }
+    }
+
+    let (split, cost) = best?;
+    (cost < leaf_cost).then_some(split)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rng;
+
+    fn triangle_soup(rng: &mut Rng, count: usize) -> (Vec<GpuVertex>, Vec<u32>) {
+        let mut vertices = Vec::new();
+        for _ in 0..count {
+            let center = rng.signed_vec3();
+            for _ in 0..3 {
+                vertices.push(GpuVertex {
+                    position: center + rng.signed_vec3() * 0.2,
+                    ..Default::default()
+                });
+            }
+        }
+        let indices = (0..vertices.len() as u32).collect();
+        (vertices, indices)
+    }
+
+    /// Distance to a triangle from either side
+    fn hit_triangle(origin: Vec3, dir: Vec3, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+        let edge_ab = b - a;
+        let edge_ac = c - a;
+        let p = dir.cross(edge_ac);
+        let det = edge_ab.dot(p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let ao = origin - a;
+        let u = ao.dot(p) * inv_det;
+        let q = ao.cross(edge_ab);
+        let v = dir.dot(q) * inv_det;
+        let t = edge_ac.dot(q) * inv_det;
+        (u >= 0.0 && v >= 0.0 && u + v <= 1.0 && t > 0.0).then_some(t)
+    }
+
+    fn hit_box(node: &BvhNode, origin: Vec3, inv_dir: Vec3) -> bool {
+        let t1 = (node.aabb_min - origin) * inv_dir;
+        let t2 = (node.aabb_max - origin) * inv_dir;
+        let near = t1.min(t2).max_element();
+        let far = t1.max(t2).min_element();
+        far >= near && far > 0.0
+    }
+
+    fn triangle(vertices: &[GpuVertex], indices: &[u32], tri: usize) -> [Vec3; 3] {
+        [0, 1, 2].map(|v| vertices[indices[tri * 3 + v] as usize].position)
+    }
+
+    fn traverse(
+        nodes: &[BvhNode],
+        vertices: &[GpuVertex],
+        indices: &[u32],
+        origin: Vec3,
+        dir: Vec3,
+    ) -> Option<f32> {
+        let inv_dir = dir.recip();
+        let mut nearest: Option<f32> = None;
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = &nodes[i];
+            if !hit_box(node, origin, inv_dir) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for tri in node.first..node.first + node.count {
+                    let t = hit_triangle(origin, dir, triangle(vertices, indices, tri as usize));
+                    if let Some(t) = t.filter(|&t| nearest.is_none_or(|n| t < n)) {
+                        nearest = Some(t);
+                    }
+                }
+            } else {
+                stack.push(node.first as usize);
+                stack.push(node.first as usize + 1);
+            }
+        }
+        nearest
+    }
+
+    #[test]
+    fn traversal_matches_linear_scan() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for count in [1, 2, 7, 100, 1000] {
+            let (vertices, original) = triangle_soup(&mut rng, count);
+            let mut indices = original.clone();
+            let nodes = build_mesh(&vertices, &mut indices);
+
+            let mut hits = 0;
+            for _ in 0..500 {
+                let origin = rng.signed_vec3().normalize_or(Vec3::X) * 3.0;
+                let dir = (rng.signed_vec3() - origin).normalize();
+
+                let linear = (0..count)
+                    .filter_map(|tri| {
+                        hit_triangle(origin, dir, triangle(&vertices, &original, tri))
+                    })
+                    .min_by(f32::total_cmp);
+                let bvh = traverse(&nodes, &vertices, &indices, origin, dir);
+                assert_eq!(linear, bvh, "{count} triangles, ray {origin} {dir}");
+                hits += linear.is_some() as usize;
+            }
+            assert!(hits > 0);
+        }
+    }
+
+    #[test]
+    fn every_triangle_is_in_one_leaf() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for count in [0, 1, 5, 64, 1000] {
+            let (vertices, mut indices) = triangle_soup(&mut rng, count);
+            let nodes = build_mesh(&vertices, &mut indices);
+
+            let mut seen = vec![0; count];
+            for node in nodes.iter().filter(|node| node.count > 0) {
+                for tri in node.first..node.first + node.count {
+                    seen[indices[tri as usize * 3] as usize / 3] += 1;
+                }
+            }
+            assert!(seen.iter().all(|&n| n == 1), "{count} triangles");
+        }
+    }
+
+    #[test]
+    fn children_come_after_their_parent() {
+        let mut rng = Rng(0x853c49e6748fea9b);
+        let (vertices, mut indices) = triangle_soup(&mut rng, 500);
+        let nodes = build_mesh(&vertices, &mut indices);
+        for (i, node) in nodes.iter().enumerate() {
+            if node.count == 0 {
+                assert!(node.first as usize > i);
+            }
+        }
+    }
+
+    #[test]
+    fn level_refit_matches_serial_refit() {
+        let mut rng = Rng(0xda942042e4dd58b5);
+        for count in [0, 1, 2, 33, 500] {
+            let (mut vertices, mut indices) = triangle_soup(&mut rng, count);
+            let mut nodes = build_mesh(&vertices, &mut indices);
+            let depth = depth(&nodes);
+            assert!(depth as usize <= MAX_DEPTH);
+
+            for vertex in &mut vertices {
+                vertex.position += rng.signed_vec3() * 0.5;
+            }
+            let bounds: Vec<Bounds> = (0..count)
+                .map(|tri| Bounds::from_points(&triangle(&vertices, &indices, tri)))
+                .collect();
+            let mut serial = nodes.clone();
+            refit(&mut serial, &(0..count as u32).collect::<Vec<_>>(), &bounds);
+
+            // Like the refit shaders, with parents visited before their children so
+            // every pass only moves the bounds up one level
+            for node in nodes.iter_mut() {
+                if node.count > 0 || node.first == 0 {
+                    let b = (node.first..node.first + node.count)
+                        .fold(Bounds::EMPTY, |b, tri| b.union(bounds[tri as usize]));
+                    node.aabb_min = b.min;
+                    node.aabb_max = b.max;
+                }
+            }
+            for _ in 1..depth {
+                for i in 0..nodes.len() {
+                    let node = nodes[i];
+                    if node.count == 0 && node.first != 0 {
+                        let left = nodes[node.first as usize];
+                        let right = nodes[node.first as usize + 1];
+                        nodes[i].aabb_min = left.aabb_min.min(right.aabb_min);
+                        nodes[i].aabb_max = left.aabb_max.max(right.aabb_max);
+                    }
+                }
+            }
+
+            assert!(nodes == serial, "{count} triangles");
+        }
+    }
+}
@@ -0,0 +1,1067 @@
+use bevy::render::render_resource::TextureFormat;
+
+use crate::data::Texture;
+
+// CPU decoders for block compressed images, only needed when textures go through the
+// storage buffer. Every block is decoded into 4x4 RGBA8 texels and the channels the
+// target format keeps are written out.
+
+type Block = [[u8; 4]; 16];
+
+/// Format [`decompress`] turns `format` into, none when it can't be decoded
+pub fn decompressed_format(format: TextureFormat) -> Option<u32> {
+    use TextureFormat as F;
+
+    Some(match format {
+        F::Bc1RgbaUnorm
+        | F::Bc2RgbaUnorm
+        | F::Bc3RgbaUnorm
+        | F::Bc7RgbaUnorm
+        | F::Etc2Rgb8Unorm
+        | F::Etc2Rgb8A1Unorm
+        | F::Etc2Rgba8Unorm => Texture::FORMAT_RGBA8,
+        F::Bc1RgbaUnormSrgb
+        | F::Bc2RgbaUnormSrgb
+        | F::Bc3RgbaUnormSrgb
+        | F::Bc7RgbaUnormSrgb
+        | F::Etc2Rgb8UnormSrgb
+        | F::Etc2Rgb8A1UnormSrgb
+        | F::Etc2Rgba8UnormSrgb => Texture::FORMAT_RGBA8_SRGB,
+        F::Bc4RUnorm | F::EacR11Unorm => Texture::FORMAT_R8,
+        F::Bc5RgUnorm | F::EacRg11Unorm => Texture::FORMAT_RG8,
+        _ => return None,
+    })
+}
+
+/// Decodes the first level of `data` into tightly packed texels of [`decompressed_format`]
+pub fn decompress(format: TextureFormat, width: u32, height: u32, data: &[u8]) -> Option<Vec<u8>> {
+    use TextureFormat as F;
+
+    let (decode, block_size): (fn(&[u8]) -> Block, usize) = match format {
+        F::Bc1RgbaUnorm | F::Bc1RgbaUnormSrgb => (|b| bc1(b, true), 8),
+        F::Bc2RgbaUnorm | F::Bc2RgbaUnormSrgb => (bc2, 16),
+        F::Bc3RgbaUnorm | F::Bc3RgbaUnormSrgb => (bc3, 16),
+        F::Bc4RUnorm => (bc4, 8),
+        F::Bc5RgUnorm => (bc5, 16),
+        F::Bc7RgbaUnorm | F::Bc7RgbaUnormSrgb => (bc7, 16),
+        F::Etc2Rgb8Unorm | F::Etc2Rgb8UnormSrgb => (|b| etc2(b, false), 8),
+        F::Etc2Rgb8A1Unorm | F::Etc2Rgb8A1UnormSrgb => (|b| etc2(b, true), 8),
+        F::Etc2Rgba8Unorm | F::Etc2Rgba8UnormSrgb => (etc2_eac, 16),
+        F::EacR11Unorm => (eac_r11, 8),
+        F::EacRg11Unorm => (eac_rg11, 16),
+        _ => unreachable!(),
+    };
+    let channels = Texture::texel_size(decompressed_format(format).unwrap()) as usize;
+
+    let (width, height) = (width as usize, height as usize);
+    let blocks_x = width.div_ceil(4);
+    let blocks = data.get(..blocks_x * height.div_ceil(4) * block_size)?;
+    let mut texels = vec![0; width * height * channels];
+    for (i, block) in blocks.chunks_exact(block_size).enumerate() {
+        let (bx, by) = (i % blocks_x * 4, i / blocks_x * 4);
+        for (p, texel) in decode(block).iter().enumerate() {
+            let (x, y) = (bx + p % 4, by + p / 4);
+            if x < width && y < height {
+                let offset = (x + y * width) * channels;
+                texels[offset..offset + channels].copy_from_slice(&texel[..channels]);
+            }
+        }
+    }
+
+    Some(texels)
+}
+
+// ---- BC ----
+
+fn rgb565(c: u16) -> [u8; 4] {
+    let r = (c >> 11) as u8 & 0x1f;
+    let g = (c >> 5) as u8 & 0x3f;
+    let b = c as u8 & 0x1f;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2, 255]
+}
+
+fn mix(a: [u8; 4], b: [u8; 4], wa: u32, wb: u32) -> [u8; 4] {
+    [0, 1, 2, 3].map(|c| ((a[c] as u32 * wa + b[c] as u32 * wb) / (wa + wb)) as u8)
+}
+
+// `punch_through` is false for the color half of BC2 and BC3, which is always four colors
+fn bc1(block: &[u8], punch_through: bool) -> Block {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let (a, b) = (rgb565(c0), rgb565(c1));
+    let palette = if c0 > c1 || !punch_through {
+        [a, b, mix(a, b, 2, 1), mix(a, b, 1, 2)]
+    } else {
+        [a, b, mix(a, b, 1, 1), [0; 4]]
+    };
+
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    std::array::from_fn(|p| palette[(indices >> (p * 2)) as usize & 3])
+}
+
+fn bc2(block: &[u8]) -> Block {
+    let mut texels = bc1(&block[8..], false);
+    let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+    for (p, texel) in texels.iter_mut().enumerate() {
+        texel[3] = (alpha >> (p * 4)) as u8 & 0xf;
+        texel[3] |= texel[3] << 4;
+    }
+    texels
+}
+
+fn bc3(block: &[u8]) -> Block {
+    let mut texels = bc1(&block[8..], false);
+    for (texel, alpha) in texels.iter_mut().zip(bc4_channel(&block[..8])) {
+        texel[3] = alpha;
+    }
+    texels
+}
+
+fn bc4(block: &[u8]) -> Block {
+    bc4_channel(block).map(|r| [r, 0, 0, 255])
+}
+
+fn bc5(block: &[u8]) -> Block {
+    let r = bc4_channel(&block[..8]);
+    let g = bc4_channel(&block[8..]);
+    std::array::from_fn(|p| [r[p], g[p], 0, 255])
+}
+
+fn bc4_channel(block: &[u8]) -> [u8; 16] {
+    let (a, b) = (block[0] as u32, block[1] as u32);
+    let palette: [u8; 8] = if a > b {
+        std::array::from_fn(|i| match i {
+            0 => a as u8,
+            1 => b as u8,
+            i => ((a * (8 - i as u32) + b * (i as u32 - 1)) / 7) as u8,
+        })
+    } else {
+        std::array::from_fn(|i| match i {
+            0 => a as u8,
+            1 => b as u8,
+            6 => 0,
+            7 => 255,
+            i => ((a * (6 - i as u32) + b * (i as u32 - 1)) / 5) as u8,
+        })
+    };
+
+    let mut bits = [0; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(bits);
+    std::array::from_fn(|p| palette[(indices >> (p * 3)) as usize & 7])
+}
+
+// ---- BC7 ----
+
+// Subset of each texel for the two subset partitions, one bit per texel
+const BC7_PARTITIONS_2: [u16; 64] = [
+    0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80, 0xc800, 0xffec, 0xfe80, 0xe800,
+    0xffe8, 0xff00, 0xfff0, 0xf000, 0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+    0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c, 0xaaaa, 0xf0f0, 0x5a5a, 0x33cc,
+    0x3c3c, 0x55aa, 0x9696, 0xa55a, 0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+    0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c, 0x9336, 0x9cc6, 0x817e, 0xe718,
+    0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+
+// Subset of each texel for the three subset partitions, two bits per texel
+const BC7_PARTITIONS_3: [u32; 64] = [
+    0xaa685050, 0x6a5a5040, 0x5a5a4200, 0x5450a0a8, 0xa5a50000, 0xa0a05050, 0x5555a0a0, 0x5a5a5050,
+    0xaa550000, 0xaa555500, 0xaaaa5500, 0x90909090, 0x94949494, 0xa4a4a4a4, 0xa9a59450, 0x2a0a4250,
+    0xa5945040, 0x0a425054, 0xa5a5a500, 0x55a0a0a0, 0xa8a85454, 0x6a6a4040, 0xa4a45000, 0x1a1a0500,
+    0x0050a4a4, 0xaaa59090, 0x14696914, 0x69691400, 0xa08585a0, 0xaa821414, 0x50a4a450, 0x6a5a0200,
+    0xa9a58000, 0x5090a0a8, 0xa8a09050, 0x24242424, 0x00aa5500, 0x24924924, 0x24499224, 0x50a50a50,
+    0x500aa550, 0xaaaa4444, 0x66660000, 0xa5a0a5a0, 0x50a050a0, 0x69286928, 0x44aaaa44, 0x66666600,
+    0xaa444444, 0x54a854a8, 0x95809580, 0x96969600, 0xa85454a8, 0x80959580, 0xaa141414, 0x96960000,
+    0xaaaa1414, 0xa05050a0, 0xa0a5a5a0, 0x96000000, 0x40804080, 0xa9a8a9a8, 0xaaaaaa44, 0x2a4a5254,
+];
+
+// Texels whose index drops its top bit, the first subset's is always texel 0
+const BC7_ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 2, 8, 2, 2, 8, 8, 15, 2, 8,
+    2, 2, 8, 8, 2, 2, 15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6, 6, 2, 6, 8, 15, 15, 2,
+    2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+const BC7_ANCHORS_3_SECOND: [u8; 64] = [
+    3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3, 3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5,
+    15, 15, 8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15, 3, 15, 5, 5, 5, 8, 5, 10, 5,
+    10, 8, 13, 15, 12, 3, 3,
+];
+const BC7_ANCHORS_3_THIRD: [u8; 64] = [
+    15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8, 15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6,
+    10, 15, 15, 10, 8, 15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8, 15, 3, 15, 15, 15,
+    15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+];
+
+const BC7_WEIGHTS_2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS_3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    endpoint_p_bits: bool,
+    shared_p_bits: bool,
+    index_bits: u32,
+    index2_bits: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+const fn bc7_mode(
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    endpoint_p_bits: bool,
+    shared_p_bits: bool,
+    index_bits: u32,
+    index2_bits: u32,
+) -> Bc7Mode {
+    Bc7Mode {
+        subsets,
+        partition_bits,
+        rotation_bits,
+        index_selection_bits,
+        color_bits,
+        alpha_bits,
+        endpoint_p_bits,
+        shared_p_bits,
+        index_bits,
+        index2_bits,
+    }
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    bc7_mode(3, 4, 0, 0, 4, 0, true, false, 3, 0),
+    bc7_mode(2, 6, 0, 0, 6, 0, false, true, 3, 0),
+    bc7_mode(3, 6, 0, 0, 5, 0, false, false, 2, 0),
+    bc7_mode(2, 6, 0, 0, 7, 0, true, false, 2, 0),
+    bc7_mode(1, 0, 2, 1, 5, 6, false, false, 2, 3),
+    bc7_mode(1, 0, 2, 0, 7, 8, false, false, 2, 2),
+    bc7_mode(1, 0, 0, 0, 7, 7, true, false, 4, 0),
+    bc7_mode(2, 6, 0, 0, 5, 5, true, false, 2, 0),
+];
+
+struct Bits {
+    value: u128,
+    offset: u32,
+}
+
+impl Bits {
+    fn read(&mut self, count: u32) -> u32 {
+        let bits =
+            self.value.checked_shr(self.offset).unwrap_or(0) as u32 & ((1u64 << count) - 1) as u32;
+        self.offset += count;
+        bits
+    }
+}
+
+fn bc7(block: &[u8]) -> Block {
+    let mut bits = Bits {
+        value: u128::from_le_bytes(block.try_into().unwrap()),
+        offset: 0,
+    };
+
+    // The mode is the position of the lowest set bit, reserved blocks decode to transparent black
+    let mode_index = block[0].trailing_zeros() as usize;
+    if mode_index >= BC7_MODES.len() {
+        return [[0; 4]; 16];
+    }
+    let mode = &BC7_MODES[mode_index];
+    bits.offset = mode_index as u32 + 1;
+
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let index_selection = bits.read(mode.index_selection_bits);
+
+    let mut endpoints = [[0u32; 4]; 6];
+    for c in 0..3 {
+        for endpoint in endpoints.iter_mut().take(mode.subsets * 2) {
+            endpoint[c] = bits.read(mode.color_bits);
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(mode.subsets * 2) {
+        endpoint[3] = if mode.alpha_bits > 0 {
+            bits.read(mode.alpha_bits)
+        } else {
+            255
+        };
+    }
+
+    // P-bits add a shared lowest bit to every channel of an endpoint
+    let mut color_bits = mode.color_bits;
+    let mut alpha_bits = mode.alpha_bits;
+    if mode.endpoint_p_bits || mode.shared_p_bits {
+        let mut p_bits = [0; 6];
+        if mode.endpoint_p_bits {
+            for p in p_bits.iter_mut().take(mode.subsets * 2) {
+                *p = bits.read(1);
+            }
+        } else {
+            for subset in 0..mode.subsets {
+                let p = bits.read(1);
+                p_bits[subset * 2] = p;
+                p_bits[subset * 2 + 1] = p;
+            }
+        }
+
+        for (endpoint, p) in endpoints.iter_mut().zip(p_bits).take(mode.subsets * 2) {
+            for v in &mut endpoint[..3] {
+                *v = *v << 1 | p;
+            }
+            if mode.alpha_bits > 0 {
+                endpoint[3] = endpoint[3] << 1 | p;
+            }
+        }
+        color_bits += 1;
+        if mode.alpha_bits > 0 {
+            alpha_bits += 1;
+        }
+    }
+
+    let expand = |v: u32, bits: u32| (v << (8 - bits)) | (v >> (2 * bits - 8));
+    for endpoint in endpoints.iter_mut().take(mode.subsets * 2) {
+        for v in &mut endpoint[..3] {
+            *v = expand(*v, color_bits);
+        }
+        if mode.alpha_bits > 0 {
+            endpoint[3] = expand(endpoint[3], alpha_bits);
+        }
+    }
+
+    let subset_of = |p: usize| match mode.subsets {
+        2 => (BC7_PARTITIONS_2[partition] >> p) as usize & 1,
+        3 => (BC7_PARTITIONS_3[partition] >> (p * 2)) as usize & 3,
+        _ => 0,
+    };
+    let is_anchor = |p: usize| {
+        p == 0
+            || match mode.subsets {
+                2 => p == BC7_ANCHORS_2[partition] as usize,
+                3 => {
+                    p == BC7_ANCHORS_3_SECOND[partition] as usize
+                        || p == BC7_ANCHORS_3_THIRD[partition] as usize
+                }
+                _ => false,
+            }
+    };
+
+    let mut indices = [0; 16];
+    for (p, index) in indices.iter_mut().enumerate() {
+        *index = bits.read(mode.index_bits - is_anchor(p) as u32) as usize;
+    }
+    let mut indices2 = [0; 16];
+    if mode.index2_bits > 0 {
+        for (p, index) in indices2.iter_mut().enumerate() {
+            *index = bits.read(mode.index2_bits - (p == 0) as u32) as usize;
+        }
+    }
+
+    let weights = |bits: u32| match bits {
+        2 => &BC7_WEIGHTS_2[..],
+        3 => &BC7_WEIGHTS_3[..],
+        _ => &BC7_WEIGHTS_4[..],
+    };
+    let interpolate = |a: u32, b: u32, w: u32| ((64 - w) * a + w * b + 32) >> 6;
+
+    std::array::from_fn(|p| {
+        let subset = subset_of(p);
+        let (e0, e1) = (endpoints[subset * 2], endpoints[subset * 2 + 1]);
+
+        // Modes with a second index set use it for alpha, unless the selection bit swaps them
+        let (color_weight, alpha_weight) = if mode.index2_bits == 0 {
+            let w = weights(mode.index_bits)[indices[p]];
+            (w, w)
+        } else if index_selection == 0 {
+            (
+                weights(mode.index_bits)[indices[p]],
+                weights(mode.index2_bits)[indices2[p]],
+            )
+        } else {
+            (
+                weights(mode.index2_bits)[indices2[p]],
+                weights(mode.index_bits)[indices[p]],
+            )
+        };
+
+        let mut texel = [0, 1, 2, 3].map(|c| {
+            let w = if c == 3 { alpha_weight } else { color_weight };
+            interpolate(e0[c], e1[c], w) as u8
+        });
+        if rotation > 0 {
+            texel.swap(rotation as usize - 1, 3);
+        }
+        texel
+    })
+}
+
+// ---- ETC2 ----
+
+const ETC_MODIFIERS: [[i32; 2]; 8] = [
+    [2, 8],
+    [5, 17],
+    [9, 29],
+    [13, 42],
+    [18, 60],
+    [24, 80],
+    [33, 106],
+    [47, 183],
+];
+const ETC_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+    [-3, -6, -9, -15, 2, 5, 8, 14],
+    [-3, -7, -10, -13, 2, 6, 9, 12],
+    [-2, -5, -8, -13, 1, 4, 7, 12],
+    [-2, -4, -6, -13, 1, 3, 5, 12],
+    [-3, -6, -8, -12, 2, 5, 7, 11],
+    [-3, -7, -9, -11, 2, 6, 8, 10],
+    [-4, -7, -8, -11, 3, 6, 7, 10],
+    [-3, -5, -8, -11, 2, 4, 7, 10],
+    [-2, -6, -8, -10, 1, 5, 7, 9],
+    [-2, -5, -8, -10, 1, 4, 7, 9],
+    [-2, -4, -8, -10, 1, 3, 7, 9],
+    [-2, -5, -7, -10, 1, 4, 6, 9],
+    [-3, -4, -7, -10, 2, 3, 6, 9],
+    [-1, -2, -3, -10, 0, 1, 2, 9],
+    [-4, -6, -8, -9, 3, 5, 7, 8],
+    [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+fn extend4(v: u64) -> i32 {
+    (v as i32 & 0xf) * 17
+}
+
+fn extend5(v: u64) -> i32 {
+    let v = v as i32 & 0x1f;
+    v << 3 | v >> 2
+}
+
+fn rgb(r: i32, g: i32, b: i32, alpha: u8) -> [u8; 4] {
+    let [r, g, b] = [r, g, b].map(|c| c.clamp(0, 255) as u8);
+    [r, g, b, alpha]
+}
+
+// `punch_through` is the RGB8A1 variant, where bit 33 marks opaque blocks instead of picking a mode
+fn etc2(block: &[u8], punch_through: bool) -> Block {
+    let b = u64::from_be_bytes(block.try_into().unwrap());
+    let differential = punch_through || (b >> 33) & 1 != 0;
+    let opaque = !punch_through || (b >> 33) & 1 != 0;
+    let flip = (b >> 32) & 1 != 0;
+
+    // Texels are stored column major with the high and low index bits in separate halves
+    let index = |x: usize, y: usize| {
+        let p = x * 4 + y;
+        ((b >> (16 + p)) & 1) << 1 | (b >> p) & 1
+    };
+    let paint = |colors: [[i32; 3]; 4]| -> Block {
+        std::array::from_fn(|p| {
+            let i = index(p % 4, p / 4) as usize;
+            if !opaque && i == 2 {
+                return [0; 4];
+            }
+            rgb(colors[i][0], colors[i][1], colors[i][2], 255)
+        })
+    };
+
+    let signed3 = |v: u64| ((v as i32 & 7) << 29) >> 29;
+    let (r, g, bl) = ((b >> 59) & 0x1f, (b >> 51) & 0x1f, (b >> 43) & 0x1f);
+    let (dr, dg, db) = (signed3(b >> 56), signed3(b >> 48), signed3(b >> 40));
+
+    if differential && !(0..32).contains(&(r as i32 + dr)) {
+        // T mode
+        let c0 = [
+            extend4((b >> 57) & 0xc | (b >> 56) & 0x3),
+            extend4(b >> 52),
+            extend4(b >> 48),
+        ];
+        let c1 = [extend4(b >> 44), extend4(b >> 40), extend4(b >> 36)];
+        let d = ETC_DISTANCES[((b >> 33) & 0x6 | (b >> 32) & 0x1) as usize];
+        return paint([c0, c1.map(|c| c + d), c1, c1.map(|c| c - d)]);
+    }
+    if differential && !(0..32).contains(&(g as i32 + dg)) {
+        // H mode
+        let c0 = [
+            extend4(b >> 59),
+            extend4((b >> 55) & 0xe | (b >> 52) & 0x1),
+            extend4((b >> 48) & 0x8 | (b >> 47) & 0x7),
+        ];
+        let c1 = [extend4(b >> 43), extend4(b >> 39), extend4(b >> 35)];
+        let value = |c: [i32; 3]| c[0] << 16 | c[1] << 8 | c[2];
+        let d = ETC_DISTANCES
+            [((b >> 32) & 0x4 | (b >> 31) & 0x2) as usize | (value(c0) >= value(c1)) as usize];
+        return paint([
+            c0.map(|c| c + d),
+            c0.map(|c| c - d),
+            c1.map(|c| c + d),
+            c1.map(|c| c - d),
+        ]);
+    }
+    if differential && !(0..32).contains(&(bl as i32 + db)) {
+        // Planar mode, a gradient from three colors
+        let extend6 = |v: u64| {
+            let v = v as i32 & 0x3f;
+            v << 2 | v >> 4
+        };
+        let extend7 = |v: u64| {
+            let v = v as i32 & 0x7f;
+            v << 1 | v >> 6
+        };
+        let o = [
+            extend6(b >> 57),
+            extend7((b >> 50) & 0x40 | (b >> 49) & 0x3f),
+            extend6((b >> 43) & 0x20 | (b >> 40) & 0x18 | (b >> 39) & 0x7),
+        ];
+        let h = [
+            extend6((b >> 33) & 0x3e | (b >> 32) & 0x1),
+            extend7(b >> 25),
+            extend6(b >> 19),
+        ];
+        let v = [extend6(b >> 13), extend7(b >> 6), extend6(b)];
+        return std::array::from_fn(|p| {
+            let (x, y) = ((p % 4) as i32, (p / 4) as i32);
+            let [r, g, b] =
+                [0, 1, 2].map(|c| (x * (h[c] - o[c]) + y * (v[c] - o[c]) + 4 * o[c] + 2) >> 2);
+            rgb(r, g, b, 255)
+        });
+    }
+
+    // ETC1, two sub-blocks side by side or on top of each other
+    let bases = if differential {
+        [
+            [extend5(r), extend5(g), extend5(bl)],
+            [
+                extend5((r as i32 + dr) as u64),
+                extend5((g as i32 + dg) as u64),
+                extend5((bl as i32 + db) as u64),
+            ],
+        ]
+    } else {
+        [
+            [extend4(b >> 60), extend4(b >> 52), extend4(b >> 44)],
+            [extend4(b >> 56), extend4(b >> 48), extend4(b >> 40)],
+        ]
+    };
+    let tables = [(b >> 37) & 7, (b >> 34) & 7];
+
+    std::array::from_fn(|p| {
+        let (x, y) = (p % 4, p / 4);
+        let sub_block = if flip { y >= 2 } else { x >= 2 } as usize;
+        let [small, large] = ETC_MODIFIERS[tables[sub_block] as usize];
+        let modifier = match index(x, y) {
+            // Non opaque punch through blocks have no small modifier and are transparent for 2
+            0 if !opaque => 0,
+            2 if !opaque => return [0; 4],
+            0 => small,
+            1 => large,
+            2 => -small,
+            _ => -large,
+        };
+        let base = bases[sub_block];
+        rgb(
+            base[0] + modifier,
+            base[1] + modifier,
+            base[2] + modifier,
+            255,
+        )
+    })
+}
+
+fn etc2_eac(block: &[u8]) -> Block {
+    let mut texels = etc2(&block[8..], false);
+    for (texel, alpha) in texels.iter_mut().zip(eac(&block[..8], false)) {
+        texel[3] = alpha as u8;
+    }
+    texels
+}
+
+fn eac_r11(block: &[u8]) -> Block {
+    eac(block, true).map(|r| [unorm11(r), 0, 0, 255])
+}
+
+fn eac_rg11(block: &[u8]) -> Block {
+    let r = eac(&block[..8], true);
+    let g = eac(&block[8..], true);
+    std::array::from_fn(|p| [unorm11(r[p]), unorm11(g[p]), 0, 255])
+}
+
+fn unorm11(v: u32) -> u8 {
+    ((v * 255 + 1023) / 2047) as u8
+}
+
+// Values in 11 bits for R11 and RG11, 8 bits for alpha
+fn eac(block: &[u8], eleven_bit: bool) -> [u32; 16] {
+    let b = u64::from_be_bytes(block.try_into().unwrap());
+    let base = (b >> 56) as i32;
+    let multiplier = (b >> 52) as i32 & 0xf;
+    let modifiers = EAC_MODIFIERS[(b >> 48) as usize & 0xf];
+
+    std::array::from_fn(|p| {
+        let (x, y) = (p % 4, p / 4);
+        let modifier = modifiers[(b >> (45 - (x * 4 + y) * 3)) as usize & 7];
+        if eleven_bit {
+            let scale = if multiplier == 0 { 1 } else { multiplier * 8 };
+            (base * 8 + 4 + modifier * scale).clamp(0, 2047) as u32
+        } else {
+            (base + modifier * multiplier).clamp(0, 255) as u32
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    fn assert_decodes(format: TextureFormat, block: &[u8], expected: Block) {
+        let channels = Texture::texel_size(decompressed_format(format).unwrap()) as usize;
+        let expected: Vec<u8> = expected
+            .iter()
+            .flat_map(|texel| &texel[..channels])
+            .copied()
+            .collect();
+        assert_eq!(
+            decompress(format, 4, 4, block).unwrap(),
+            expected,
+            "{format:?}"
+        );
+    }
+
+    /// Same texel in every row, varying along x
+    fn rows(row: [[u8; 4]; 4]) -> Block {
+        std::array::from_fn(|p| row[p % 4])
+    }
+
+    fn bc1_block(c0: u16, c1: u16, indices: [u32; 16]) -> [u8; 8] {
+        let bits = (0..16).fold(0, |bits, p| bits | indices[p] << (p * 2));
+        let [c0, c1] = [c0.to_le_bytes(), c1.to_le_bytes()];
+        let bits = bits.to_le_bytes();
+        [
+            c0[0], c0[1], c1[0], c1[1], bits[0], bits[1], bits[2], bits[3],
+        ]
+    }
+
+    fn bc4_block(a: u8, b: u8, indices: [u64; 16]) -> [u8; 8] {
+        let bits = (0..16).fold(0, |bits, p| bits | indices[p] << (p * 3));
+        let mut block = [a, b, 0, 0, 0, 0, 0, 0];
+        block[2..].copy_from_slice(&bits.to_le_bytes()[..6]);
+        block
+    }
+
+    // Black to white through both interpolated colors along each row
+    const BC1_GRADIENT: [u32; 16] = [0, 2, 3, 1, 0, 2, 3, 1, 0, 2, 3, 1, 0, 2, 3, 1];
+    // 565 color of (16, 32, 8) which expands to (132, 130, 66)
+    const BC1_SOLID: u16 = 0x8408;
+    // Every entry of the eight value palette, 255 down to 0
+    const BC4_GRADIENT: [u64; 16] = [0, 2, 3, 4, 5, 6, 7, 1, 0, 2, 3, 4, 5, 6, 7, 1];
+    const BC4_GRADIENT_VALUES: [u8; 8] = [255, 218, 182, 145, 109, 72, 36, 0];
+
+    fn cat(a: [u8; 8], b: [u8; 8]) -> [u8; 16] {
+        std::array::from_fn(|i| if i < 8 { a[i] } else { b[i - 8] })
+    }
+
+    #[test]
+    fn bc1() {
+        let format = TextureFormat::Bc1RgbaUnorm;
+        assert_decodes(
+            format,
+            &bc1_block(BC1_SOLID, 0, [0; 16]),
+            [[132, 130, 66, 255]; 16],
+        );
+        assert_decodes(
+            format,
+            &bc1_block(0xffff, 0, BC1_GRADIENT),
+            rows([WHITE, [170, 170, 170, 255], [85, 85, 85, 255], BLACK]),
+        );
+
+        // With the endpoints swapped the block has three colors and transparent black
+        assert_decodes(
+            format,
+            &bc1_block(0, 0xffff, BC1_GRADIENT),
+            rows([BLACK, [127, 127, 127, 255], [0; 4], WHITE]),
+        );
+    }
+
+    #[test]
+    fn bc2() {
+        let format = TextureFormat::Bc2RgbaUnorm;
+        assert_decodes(
+            format,
+            &cat([0x88; 8], bc1_block(BC1_SOLID, 0, [0; 16])),
+            [[132, 130, 66, 136]; 16],
+        );
+
+        // Alpha nibbles 0, 5, 10 and 15 along each row
+        assert_decodes(
+            format,
+            &cat(
+                [0x50, 0xfa, 0x50, 0xfa, 0x50, 0xfa, 0x50, 0xfa],
+                bc1_block(0xffff, 0, BC1_GRADIENT),
+            ),
+            rows([
+                [255, 255, 255, 0],
+                [170, 170, 170, 85],
+                [85, 85, 85, 170],
+                [0, 0, 0, 255],
+            ]),
+        );
+    }
+
+    #[test]
+    fn bc3() {
+        let format = TextureFormat::Bc3RgbaUnorm;
+        assert_decodes(
+            format,
+            &cat(
+                bc4_block(200, 200, [0; 16]),
+                bc1_block(BC1_SOLID, 0, [0; 16]),
+            ),
+            [[132, 130, 66, 200]; 16],
+        );
+
+        // The color half always has four colors, even when the endpoints are ordered for three
+        let colors = [0, 85, 170, 255];
+        assert_decodes(
+            format,
+            &cat(
+                bc4_block(255, 0, BC4_GRADIENT),
+                bc1_block(0, 0xffff, BC1_GRADIENT),
+            ),
+            std::array::from_fn(|p| {
+                let c = colors[p % 4];
+                [c, c, c, BC4_GRADIENT_VALUES[p % 8]]
+            }),
+        );
+    }
+
+    #[test]
+    fn bc4() {
+        let format = TextureFormat::Bc4RUnorm;
+        assert_decodes(format, &bc4_block(77, 77, [0; 16]), [[77, 0, 0, 255]; 16]);
+        assert_decodes(
+            format,
+            &bc4_block(255, 0, BC4_GRADIENT),
+            std::array::from_fn(|p| [BC4_GRADIENT_VALUES[p % 8], 0, 0, 255]),
+        );
+
+        // Ordered the other way the palette has six values and explicit 0 and 255
+        let values = [0, 51, 102, 153, 204, 255, 0, 255];
+        assert_decodes(
+            format,
+            &bc4_block(0, 255, [0, 2, 3, 4, 5, 1, 6, 7, 0, 2, 3, 4, 5, 1, 6, 7]),
+            std::array::from_fn(|p| [values[p % 8], 0, 0, 255]),
+        );
+    }
+
+    #[test]
+    fn bc5() {
+        let format = TextureFormat::Bc5RgUnorm;
+        assert_decodes(
+            format,
+            &cat(bc4_block(77, 77, [0; 16]), bc4_block(200, 200, [0; 16])),
+            [[77, 200, 0, 255]; 16],
+        );
+        assert_decodes(
+            format,
+            &cat(bc4_block(255, 0, BC4_GRADIENT), bc4_block(77, 77, [0; 16])),
+            std::array::from_fn(|p| [BC4_GRADIENT_VALUES[p % 8], 77, 0, 255]),
+        );
+    }
+
+    #[derive(Default)]
+    struct BitWriter {
+        value: u128,
+        offset: u32,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u32, count: u32) {
+            self.value |= (value as u128) << self.offset;
+            self.offset += count;
+        }
+    }
+
+    #[test]
+    fn bc7_solid_in_every_mode() {
+        // Red is all ones, green zero and blue only its top bit, with every p-bit cleared
+        let expected = [
+            [247, 0, 132, 255],
+            [253, 0, 129, 255],
+            [255, 0, 132, 255],
+            [254, 0, 128, 255],
+            [255, 0, 132, 255],
+            [255, 0, 129, 255],
+            [254, 0, 128, 254],
+            [251, 0, 130, 251],
+        ];
+        for (mode_index, (mode, expected)) in BC7_MODES.iter().zip(expected).enumerate() {
+            let mut bits = BitWriter::default();
+            bits.write(1 << mode_index, mode_index as u32 + 1);
+            bits.write(
+                0,
+                mode.partition_bits + mode.rotation_bits + mode.index_selection_bits,
+            );
+            for value in [(1 << mode.color_bits) - 1, 0, 1 << (mode.color_bits - 1)] {
+                for _ in 0..mode.subsets * 2 {
+                    bits.write(value, mode.color_bits);
+                }
+            }
+            for _ in 0..mode.subsets * 2 {
+                bits.write((1 << mode.alpha_bits) - 1, mode.alpha_bits);
+            }
+
+            let block = bits.value.to_le_bytes();
+            assert_eq!(bc7(&block), [expected; 16], "mode {mode_index}");
+            assert_decodes(TextureFormat::Bc7RgbaUnorm, &block, [expected; 16]);
+        }
+    }
+
+    #[test]
+    fn bc7_gradient() {
+        // Mode 6 from black to white with every 4 bit weight, 0 to 64
+        let mut bits = BitWriter::default();
+        bits.write(1 << 6, 7);
+        for _ in 0..4 {
+            bits.write(0, 7);
+            bits.write(0x7f, 7);
+        }
+        bits.write(0, 1);
+        bits.write(1, 1);
+        bits.write(0, 3);
+        for p in 1..16 {
+            bits.write(p, 4);
+        }
+
+        let values = [
+            0, 16, 36, 52, 68, 84, 104, 120, 135, 151, 171, 187, 203, 219, 239, 255,
+        ];
+        assert_decodes(
+            TextureFormat::Bc7RgbaUnorm,
+            &bits.value.to_le_bytes(),
+            values.map(|v| [v; 4]),
+        );
+    }
+
+    #[test]
+    fn bc7_partitions() {
+        // Mode 1 with black and white subsets, partition 0 puts the right half in the second
+        let mut bits = BitWriter::default();
+        bits.write(1 << 1, 2);
+        bits.write(0, 6);
+        for _ in 0..3 {
+            for value in [0, 0, 0x3f, 0x3f] {
+                bits.write(value, 6);
+            }
+        }
+
+        let grey = [253, 253, 253, 255];
+        assert_decodes(
+            TextureFormat::Bc7RgbaUnorm,
+            &bits.value.to_le_bytes(),
+            rows([BLACK, BLACK, grey, grey]),
+        );
+    }
+
+    #[test]
+    fn bc7_reserved_mode() {
+        assert_eq!(bc7(&[0; 16]), [[0; 4]; 16]);
+    }
+
+    /// ETC2 color block from its mode bits and a 2 bit index per texel
+    fn etc_block(mut bits: u64, index: impl Fn(usize, usize) -> u64) -> [u8; 8] {
+        for (x, y) in (0..16).map(|p| (p % 4, p / 4)) {
+            let (i, p) = (index(x, y), x * 4 + y);
+            bits |= (i >> 1) << (16 + p) | (i & 1) << p;
+        }
+        bits.to_be_bytes()
+    }
+
+    // Differential base of (16, 8, 0), which becomes (134, 68, 2) with the small modifier
+    const ETC_SOLID: u64 = 16 << 59 | 8 << 51 | 1 << 33;
+
+    #[test]
+    fn etc2_individual_and_differential() {
+        let format = TextureFormat::Etc2Rgb8Unorm;
+        assert_decodes(
+            format,
+            &etc_block(ETC_SOLID, |_, _| 0),
+            [[134, 68, 2, 255]; 16],
+        );
+
+        // Individual colors of (15, 8, 0) in both halves, red clamps at 255
+        assert_decodes(
+            format,
+            &etc_block(0xff << 56 | 0x88 << 48, |_, _| 0),
+            [[255, 138, 2, 255]; 16],
+        );
+
+        // Black on the left and a differential 3 on the right, modifiers going down the rows
+        let left = [2, 8, 0, 0];
+        let right = [26, 32, 22, 16];
+        assert_decodes(
+            format,
+            &etc_block(3 << 56 | 3 << 48 | 3 << 40 | 1 << 33, |_, y| y as u64),
+            std::array::from_fn(|p| {
+                let v = if p % 4 < 2 { left } else { right }[p / 4];
+                [v, v, v, 255]
+            }),
+        );
+    }
+
+    #[test]
+    fn etc2_t_mode() {
+        // Red overflows into T mode with colors red and (136, 136, 136), a distance of 6
+        let bits = 0x1f << 59 | 3 << 56 | 1 << 47 | 1 << 43 | 1 << 39 | 1 << 33 | 1 << 32;
+        let colors = [
+            [255, 0, 0, 255],
+            [142, 142, 142, 255],
+            [136, 136, 136, 255],
+            [130, 130, 130, 255],
+        ];
+        assert_decodes(
+            TextureFormat::Etc2Rgb8Unorm,
+            &etc_block(bits, |_, y| y as u64),
+            std::array::from_fn(|p| colors[p / 4]),
+        );
+    }
+
+    #[test]
+    fn etc2_h_mode() {
+        // Green overflows into H mode with colors red and blue, a distance of 6
+        let bits = 0xf << 59 | 1 << 50 | 0xf << 35 | 1 << 33;
+        let colors = [
+            [255, 6, 6, 255],
+            [249, 0, 0, 255],
+            [6, 6, 255, 255],
+            [0, 0, 249, 255],
+        ];
+        assert_decodes(
+            TextureFormat::Etc2Rgb8Unorm,
+            &etc_block(bits, |_, y| y as u64),
+            std::array::from_fn(|p| colors[p / 4]),
+        );
+    }
+
+    #[test]
+    fn etc2_planar_mode() {
+        // Blue overflows into planar mode, black at the origin and white one block to the right
+        let bits: u64 = 1 << 42 | 0x1f << 34 | 1 << 33 | 1 << 32 | 0x7f << 25 | 0x3f << 19;
+        assert_decodes(
+            TextureFormat::Etc2Rgb8Unorm,
+            &bits.to_be_bytes(),
+            rows([0, 64, 128, 191].map(|v| [v, v, v, 255])),
+        );
+    }
+
+    #[test]
+    fn etc2_punch_through() {
+        let format = TextureFormat::Etc2Rgb8A1Unorm;
+        assert_decodes(
+            format,
+            &etc_block(ETC_SOLID, |_, _| 0),
+            [[134, 68, 2, 255]; 16],
+        );
+
+        // Without the opaque bit there's no small modifier and index 2 is transparent
+        let colors = [
+            [132, 66, 0, 255],
+            [140, 74, 8, 255],
+            [0; 4],
+            [124, 58, 0, 255],
+        ];
+        assert_decodes(
+            format,
+            &etc_block(16 << 59 | 8 << 51, |_, y| y as u64),
+            std::array::from_fn(|p| colors[p / 4]),
+        );
+    }
+
+    /// EAC block from its header and a 3 bit index per texel
+    fn eac_block(base: u64, multiplier: u64, table: u64, index: impl Fn(usize) -> u64) -> [u8; 8] {
+        let mut bits = base << 56 | multiplier << 52 | table << 48;
+        for (x, y) in (0..16).map(|p| (p % 4, p / 4)) {
+            bits |= index(x) << (45 - (x * 4 + y) * 3);
+        }
+        bits.to_be_bytes()
+    }
+
+    // Base 128 with table 13's zero modifier
+    fn eac_solid() -> [u8; 8] {
+        eac_block(128, 1, 13, |_| 4)
+    }
+
+    // Base 128 times 4 with modifiers -15, -3, 2 and 14 along each row, the same ramp in 8 and 11 bits
+    fn eac_gradient() -> [u8; 8] {
+        eac_block(128, 4, 0, |x| [3, 0, 4, 7][x])
+    }
+    const EAC_GRADIENT_VALUES: [u8; 4] = [68, 116, 136, 184];
+
+    #[test]
+    fn etc2_eac() {
+        let format = TextureFormat::Etc2Rgba8Unorm;
+        let color = etc_block(ETC_SOLID, |_, _| 0);
+        assert_decodes(format, &cat(eac_solid(), color), [[134, 68, 2, 128]; 16]);
+        assert_decodes(
+            format,
+            &cat(eac_gradient(), color),
+            rows(EAC_GRADIENT_VALUES.map(|a| [134, 68, 2, a])),
+        );
+    }
+
+    #[test]
+    fn eac_r11() {
+        let format = TextureFormat::EacR11Unorm;
+        assert_decodes(format, &eac_solid(), [[128, 0, 0, 255]; 16]);
+        assert_decodes(
+            format,
+            &eac_gradient(),
+            rows(EAC_GRADIENT_VALUES.map(|r| [r, 0, 0, 255])),
+        );
+    }
+
+    #[test]
+    fn eac_rg11() {
+        let format = TextureFormat::EacRg11Unorm;
+        assert_decodes(
+            format,
+            &cat(eac_solid(), eac_solid()),
+            [[128, 128, 0, 255]; 16],
+        );
+        assert_decodes(
+            format,
+            &cat(eac_gradient(), eac_solid()),
+            rows(EAC_GRADIENT_VALUES.map(|r| [r, 128, 0, 255])),
+        );
+    }
+
+    #[test]
+    fn partial_blocks() {
+        // 6x5 covers 2x2 blocks of red, green, blue and white, the rest of the edge blocks is cropped
+        let colors = [
+            (0xf800, [255, 0, 0, 255]),
+            (0x07e0, [0, 255, 0, 255]),
+            (0x001f, [0, 0, 255, 255]),
+            (0xffff, WHITE),
+        ];
+        let data: Vec<u8> = colors
+            .iter()
+            .flat_map(|&(color, _)| bc1_block(color, 0, [0; 16]))
+            .collect();
+
+        let texels = decompress(TextureFormat::Bc1RgbaUnorm, 6, 5, &data).unwrap();
+        assert_eq!(texels.len(), 6 * 5 * 4);
+        for (i, texel) in texels.chunks_exact(4).enumerate() {
+            let (x, y) = (i % 6, i / 6);
+            assert_eq!(texel, colors[x / 4 + y / 4 * 2].1, "texel ({x}, {y})");
+        }
+
+        assert!(decompress(TextureFormat::Bc1RgbaUnorm, 6, 5, &data[..31]).is_none());
+    }
+}
@@ -1,32 +1,189 @@
+use std::{
+    f32::consts::PI,
+    fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
 use bevy::{
-    asset::UntypedAssetId,
-    color::LinearRgba,
+    asset::{AssetId, Handle, UntypedAssetId},
+    color::{ColorToComponents, LinearRgba, Srgba},
     ecs::{component::Component, system::Resource},
-    math::{Mat4, Vec2, Vec3},
-    prelude::{Image, Mesh as BevyMesh},
+    image::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
+    log::warn,
+    math::{Mat3, Mat4, UVec4, Vec2, Vec3, Vec4},
+    pbr::StandardMaterial,
+    prelude::{Image, Mesh, Transform, Visibility},
     render::{
         extract_component::ExtractComponent,
-        mesh::VertexAttributeValues,
-        render_resource::{ShaderType, StorageBuffer},
+        mesh::{Indices, VertexAttributeValues},
+        render_resource::{
+            encase::{self, internal::WriteInto, ShaderSize},
+            PrimitiveTopology, ShaderType, StorageBuffer, VertexFormat,
+        },
+        renderer::RenderQueue,
     },
-    utils::HashMap,
+    utils::{HashMap, HashSet},
 };
 
-#[derive(Component, Default, Clone, Copy, ExtractComponent, ShaderType)]
+use crate::bvh::{self, Bounds};
+
+#[derive(Component, Default, Clone, Copy, PartialEq, ExtractComponent, ShaderType)]
+#[require(RayTraceAccumulation)]
 pub struct RayTraceSettings {
     pub bounces: u32,
     pub samples: u32,
     pub sky_color: LinearRgba,
 }
 
+/// Progress of the image accumulated by a camera, reset when the camera,
+/// scene or [`RayTraceSettings`] change
+#[derive(Component, Default, Clone, ExtractComponent)]
+pub struct RayTraceAccumulation {
+    pub(crate) samples: Arc<AtomicU32>,
+}
+
+impl RayTraceAccumulation {
+    /// Samples per pixel accumulated so far
+    pub fn samples(&self) -> u32 {
+        self.samples.load(Ordering::Relaxed)
+    }
+}
+
+/// Shape traced analytically instead of as a triangle mesh, in local space like Bevy's primitives.
+/// Takes the place of a [`Mesh3d`](bevy::prelude::Mesh3d) on the same entity, so the rasterizer can
+/// still draw one
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+#[require(Transform, Visibility)]
+pub enum RayTracePrimitive {
+    Sphere {
+        radius: f32,
+    },
+    /// Facing +Z
+    Disk {
+        radius: f32,
+    },
+    /// Facing +Z
+    Rectangle {
+        half_size: Vec2,
+    },
+    /// Along the Y axis, with caps
+    Cylinder {
+        radius: f32,
+        half_height: f32,
+    },
+}
+
+impl RayTracePrimitive {
+    pub fn bounds(&self) -> Bounds {
+        let half_extents = match *self {
+            Self::Sphere { radius } => Vec3::splat(radius),
+            Self::Disk { radius } => Vec3::new(radius, radius, 0.0),
+            Self::Rectangle { half_size } => half_size.extend(0.0),
+            Self::Cylinder {
+                radius,
+                half_height,
+            } => Vec3::new(radius, half_height, radius),
+        };
+        Bounds {
+            min: -half_extents,
+            max: half_extents,
+        }
+    }
+
+    /// World space surface area, curved shapes are treated as scaled uniformly
+    pub fn area(&self, local_to_world: &Mat4) -> f32 {
+        let m = Mat3::from_mat4(*local_to_world);
+        let planar_scale = m.x_axis.cross(m.y_axis).length();
+        let uniform_scale = m.determinant().abs().powf(2.0 / 3.0);
+        match *self {
+            Self::Sphere { radius } => 4.0 * PI * radius * radius * uniform_scale,
+            Self::Disk { radius } => PI * radius * radius * planar_scale,
+            Self::Rectangle { half_size } => 4.0 * half_size.x * half_size.y * planar_scale,
+            Self::Cylinder {
+                radius,
+                half_height,
+            } => 2.0 * PI * radius * (2.0 * half_height + radius) * uniform_scale,
+        }
+    }
+}
+
+impl From<RayTracePrimitive> for Primitive {
+    fn from(primitive: RayTracePrimitive) -> Self {
+        let (kind, size) = match primitive {
+            RayTracePrimitive::Sphere { radius } => (Self::SPHERE, Vec2::new(radius, 0.0)),
+            RayTracePrimitive::Disk { radius } => (Self::DISK, Vec2::new(radius, 0.0)),
+            RayTracePrimitive::Rectangle { half_size } => (Self::RECTANGLE, half_size),
+            RayTracePrimitive::Cylinder {
+                radius,
+                half_height,
+            } => (Self::CYLINDER, Vec2::new(radius, half_height)),
+        };
+        Self { size, kind }
+    }
+}
+
 // ---- Shader ----
-#[derive(Component, Default, Clone, Copy, ShaderType)]
+#[derive(Component, Default, Clone, Copy, PartialEq, ShaderType)]
 pub struct Object {
     pub local_to_world: Mat4,
     pub world_to_local: Mat4,
 
     pub mat: u32,
+    /// `u32::MAX` for primitives
     pub mesh: u32,
+    /// Index into the primitives buffer or `u32::MAX`
+    pub primitive: u32,
+    /// Index into the emissives buffer or `u32::MAX`
+    pub emissive: u32,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct Primitive {
+    /// Radius of spheres and disks, half size of rectangles, radius and half height of cylinders
+    pub size: Vec2,
+    pub kind: u32,
+}
+
+impl Primitive {
+    pub const SPHERE: u32 = 0;
+    pub const DISK: u32 = 1;
+    pub const RECTANGLE: u32 = 2;
+    pub const CYLINDER: u32 = 3;
+}
+
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct Emissive {
+    pub object: u32,
+    /// Start of the object's triangle area CDF in the emissive CDF buffer, unused by primitives
+    pub cdf_head: u32,
+    /// World space surface area
+    pub area: f32,
+    /// Power emitted by this and all previous emissives, normalized so the last is one
+    pub power_cdf: f32,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct Light {
+    pub position: Vec3,
+    pub kind: u32,
+    /// Spot axis, or the direction towards a directional light
+    pub direction: Vec3,
+    pub inverse_range_squared: f32,
+    /// Intensity in candela for point and spot lights, illuminance in lux for directional ones
+    pub color: Vec3,
+    pub radius: f32,
+    pub spot_scale: f32,
+    pub spot_offset: f32,
+}
+
+impl Light {
+    pub const POINT: u32 = 0;
+    pub const SPOT: u32 = 1;
+    pub const DIRECTIONAL: u32 = 2;
 }
 
 #[derive(Component, Default, Clone, Copy, ShaderType)]
@@ -35,24 +192,89 @@ pub struct Material {
     pub albedo_texture: u32,
     pub emissive: Vec3,
     pub emissive_texture: u32,
+    /// How much of the camera exposure applies to `emissive`, like Bevy's rasterizer
+    pub emissive_exposure_weight: f32,
     pub roughness: f32,
     pub metallic: f32,
     pub metallic_roughness_texture: u32,
     pub reflectance: f32,
     pub normal_map_texture: u32,
+    pub flags: u32,
+}
+
+impl Material {
+    pub const FLIP_NORMAL_MAP_Y: u32 = 1;
+
+    /// Textures missing from `handle_to_texture` fall back to the constant factors
+    pub fn from_standard_material(
+        material: &StandardMaterial,
+        handle_to_texture: &HashMap<UntypedAssetId, usize>,
+    ) -> Self {
+        let texture = |handle: &Option<Handle<Image>>| {
+            handle
+                .as_ref()
+                .and_then(|handle| handle_to_texture.get(&handle.id().untyped()))
+                .map_or(u32::MAX, |v| *v as u32)
+        };
+
+        Self {
+            albedo: material.base_color.to_linear().to_vec3(),
+            albedo_texture: texture(&material.base_color_texture),
+            emissive: material.emissive.to_vec3(),
+            emissive_texture: texture(&material.emissive_texture),
+            emissive_exposure_weight: material.emissive_exposure_weight,
+            roughness: material.perceptual_roughness,
+            metallic: material.metallic,
+            metallic_roughness_texture: texture(&material.metallic_roughness_texture),
+            reflectance: material.reflectance,
+            normal_map_texture: texture(&material.normal_map_texture),
+            flags: if material.flip_normal_map_y {
+                Material::FLIP_NORMAL_MAP_Y
+            } else {
+                0
+            },
+        }
+    }
 }
 
 #[derive(Component, Default, Clone, Copy, ShaderType)]
 pub struct Texture {
     pub width: u32,
     pub height: u32,
+    /// Word offset into the texture data
     pub offset: u32,
+    /// One of the `FORMAT_` constants
     pub format: u32,
+    pub address_mode_u: u32,
+    pub address_mode_v: u32,
+    pub filter_mode: u32,
+    /// Levels stored one after another from `offset`, each half the size of the last
+    pub mip_levels: u32,
+}
+
+impl Texture {
+    pub const ADDRESS_CLAMP: u32 = 0;
+    pub const ADDRESS_REPEAT: u32 = 1;
+    pub const ADDRESS_MIRROR: u32 = 2;
+
+    pub const FILTER_MAG_LINEAR: u32 = 1;
+    pub const FILTER_MIN_LINEAR: u32 = 2;
+    pub const FILTER_MIPMAP_LINEAR: u32 = 4;
+
+    pub const FORMAT_R8: u32 = 1;
+    pub const FORMAT_RG8: u32 = 2;
+    pub const FORMAT_RGBA8: u32 = 3;
+    pub const FORMAT_RGBA16F: u32 = 4;
+    pub const FORMAT_RGB9E5: u32 = 5;
+    pub const FORMAT_RGBA32F: u32 = 6;
+    /// Stored like [`Texture::FORMAT_RGBA8`] with sRGB encoded color
+    pub const FORMAT_RGBA8_SRGB: u32 = 7;
 }
 
 #[derive(Default)]
 pub struct TextureData {
-    pub data: Vec<f32>,
+    /// Texels in their native formats packed into words
+    pub data: Vec<u32>,
 }
 
 #[derive(Component, Default, Clone, Copy, ShaderType)]
@@ -62,99 +284,1272 @@ pub struct GpuMesh {
 
     pub ihead: u32,
     pub vhead: u32,
+    pub nhead: u32,
     pub tri_count: u32,
 }
 
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct BvhNode {
+    pub aabb_min: Vec3,
+    /// Index of the left child, or of the first primitive for leaves
+    pub first: u32,
+    pub aabb_max: Vec3,
+    /// Primitive count, zero for interior nodes
+    pub count: u32,
+}
+
 #[derive(Default, Clone, Copy, ShaderType)]
 pub struct GpuVertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub uv: Vec2,
+    /// Tangent with the bitangent sign in `w`, zero when the mesh has none
+    pub tangent: Vec4,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct SkinVertex {
+    pub joints: UVec4,
+    pub weights: Vec4,
+}
+
+/// Difference to the base mesh of one vertex in a morph target
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct MorphDelta {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub tangent: Vec3,
+}
+
+/// Deforms one skinned or morphed object into its own copy of the mesh, ranges are in elements
+#[derive(Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct SkinInstance {
+    /// Bind pose vertices and their skin
+    pub src_vhead: u32,
+    pub skin_head: u32,
+    pub vertex_count: u32,
+
+    /// Zero when the object is only morphed
+    pub joint_head: u32,
+    pub joint_count: u32,
+
+    /// Targets are blended before skinning
+    pub morph_head: u32,
+    pub weight_head: u32,
+    pub morph_target_count: u32,
+
+    /// The object's copy, its hierarchy is refit after skinning
+    pub dst_vhead: u32,
+    pub ihead: u32,
+    pub nhead: u32,
+    pub node_count: u32,
 }
 
+#[derive(Clone)]
 pub struct CpuMesh {
     pub aabb_min: Vec3,
     pub aabb_max: Vec3,
 
     pub indices: Vec<u32>,
     pub vertices: Vec<GpuVertex>,
+    pub nodes: Vec<BvhNode>,
+    /// Levels of `nodes`, a deformed copy is refit one level at a time
+    pub depth: u32,
+
+    /// Empty unless the mesh has joint indices and weights
+    pub skin: Vec<SkinVertex>,
+    /// Bind pose bounds of the vertices every joint influences
+    pub joint_bounds: Vec<Bounds>,
+    /// Every vertex of the first target followed by the next one
+    pub morph_targets: Vec<MorphDelta>,
+    /// Bounds of the position deltas of every target
+    pub morph_bounds: Vec<Bounds>,
+}
+
+#[derive(Debug)]
+pub enum MeshConversionError {
+    MissingPositions,
+    UnsupportedTopology(PrimitiveTopology),
+    InvalidIndices,
+    UnsupportedFormat(&'static str, VertexFormat),
+    MorphTargetSize { vertices: usize, floats: usize },
+}
+
+impl fmt::Display for MeshConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPositions => write!(f, "it has no positions"),
+            Self::UnsupportedTopology(topology) => write!(f, "{topology:?} is not supported"),
+            Self::InvalidIndices => write!(f, "an index is out of bounds"),
+            Self::UnsupportedFormat(attribute, format) => {
+                write!(f, "{attribute} in {format:?} is not supported")
+            }
+            Self::MorphTargetSize { vertices, floats } => write!(
+                f,
+                "its morph targets have {floats} floats each instead of nine for each of its {vertices} vertices"
+            ),
+        }
+    }
+}
+
+impl CpuMesh {
+    /// Converts a triangle list or strip, computing normals and zeroing UVs when they are missing
+    pub fn from_mesh(mesh: &Mesh) -> Result<Self, MeshConversionError> {
+        let positions = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .ok_or(MeshConversionError::MissingPositions)?;
+        let positions = vec3_attribute(positions).ok_or(MeshConversionError::UnsupportedFormat(
+            "position",
+            positions.into(),
+        ))?;
+
+        // Non-indexed meshes draw their vertices in order
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(Indices::U16(indices)) => indices.iter().map(|&i| i as u32).collect(),
+            Some(Indices::U32(indices)) => indices.clone(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let indices = match mesh.primitive_topology() {
+            PrimitiveTopology::TriangleList => {
+                let mut indices = indices;
+                indices.truncate(indices.len() / 3 * 3);
+                indices
+            }
+            PrimitiveTopology::TriangleStrip => {
+                let restart = match mesh.indices() {
+                    Some(Indices::U16(_)) => u16::MAX as u32,
+                    _ => u32::MAX,
+                };
+                strip_to_list(&indices, restart)
+            }
+            topology => return Err(MeshConversionError::UnsupportedTopology(topology)),
+        };
+        if indices.iter().any(|&i| i as usize >= positions.len()) {
+            return Err(MeshConversionError::InvalidIndices);
+        }
+
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(normals) => vec3_attribute(normals).ok_or(
+                MeshConversionError::UnsupportedFormat("normal", normals.into()),
+            )?,
+            None => smooth_normals(&positions, &indices),
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(uvs) => vec2_attribute(uvs)
+                .ok_or(MeshConversionError::UnsupportedFormat("uv", uvs.into()))?,
+            None => vec![Vec2::ZERO; positions.len()],
+        };
+
+        // Generated the same way Bevy does for normal mapped meshes
+        let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+            Some(tangents) => tangent_attribute(tangents),
+            None => mesh
+                .clone()
+                .with_generated_tangents()
+                .ok()
+                .and_then(|mesh| {
+                    mesh.attribute(Mesh::ATTRIBUTE_TANGENT)
+                        .and_then(tangent_attribute)
+                }),
+        };
+
+        let joints = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX) {
+            Some(VertexAttributeValues::Uint16x4(joints)) => Some(joints),
+            Some(joints) => {
+                return Err(MeshConversionError::UnsupportedFormat(
+                    "joint index",
+                    joints.into(),
+                ))
+            }
+            None => None,
+        };
+        let weights = match mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT) {
+            Some(VertexAttributeValues::Float32x4(weights)) => Some(weights),
+            Some(weights) => {
+                return Err(MeshConversionError::UnsupportedFormat(
+                    "joint weight",
+                    weights.into(),
+                ))
+            }
+            None => None,
+        };
+
+        let mut cpu = CpuMesh {
+            aabb_min: Vec3::INFINITY,
+            aabb_max: Vec3::NEG_INFINITY,
+            indices,
+            vertices: Vec::with_capacity(positions.len()),
+            nodes: Vec::new(),
+            depth: 0,
+            skin: Vec::new(),
+            joint_bounds: Vec::new(),
+            morph_targets: Vec::new(),
+            morph_bounds: Vec::new(),
+        };
+
+        for (i, &position) in positions.iter().enumerate() {
+            cpu.aabb_min = cpu.aabb_min.min(position);
+            cpu.aabb_max = cpu.aabb_max.max(position);
+
+            cpu.vertices.push(GpuVertex {
+                position,
+                normal: normals.get(i).copied().unwrap_or(Vec3::ZERO),
+                uv: uvs.get(i).copied().unwrap_or(Vec2::ZERO),
+                tangent: tangents
+                    .as_ref()
+                    .and_then(|tangents| tangents.get(i).copied())
+                    .unwrap_or(Vec4::ZERO),
+            });
+        }
+        cpu.nodes = bvh::build_mesh(&cpu.vertices, &mut cpu.indices);
+        cpu.depth = bvh::depth(&cpu.nodes);
+
+        if let (Some(joints), Some(weights)) = (joints, weights) {
+            for (i, &position) in positions.iter().enumerate() {
+                let joints = joints.get(i).copied().unwrap_or_default();
+                let weights = weights.get(i).copied().unwrap_or_default();
+                for (&joint, &weight) in joints.iter().zip(&weights) {
+                    if weight <= 0.0 {
+                        continue;
+                    }
+
+                    let joint = joint as usize;
+                    if cpu.joint_bounds.len() <= joint {
+                        cpu.joint_bounds.resize(joint + 1, Bounds::EMPTY);
+                    }
+                    cpu.joint_bounds[joint] = cpu.joint_bounds[joint].grow(position);
+                }
+
+                cpu.skin.push(SkinVertex {
+                    joints: UVec4::from_array(joints.map(u32::from)),
+                    weights: Vec4::from_array(weights),
+                });
+            }
+        }
+
+        Ok(cpu)
+    }
+
+    /// Reads the targets Bevy packs into a [`MorphTargetImage`](bevy::render::mesh::morph::MorphTargetImage),
+    /// nine floats per vertex with every target in its own layer. Images made for a
+    /// different number of vertices leave the mesh without targets
+    pub fn set_morph_targets(&mut self, image: &Image) -> Result<(), MeshConversionError> {
+        let vertex_count = self.vertices.len();
+        let layer = (image.width() * image.height()) as usize;
+        let target_count = image.texture_descriptor.size.depth_or_array_layers as usize;
+        let floats: Vec<f32> = image
+            .data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        self.morph_targets.clear();
+        self.morph_bounds.clear();
+
+        // Every layer is padded to a rectangle, so it may hold more than the vertices need
+        let per_target = layer.min(floats.len() / target_count.max(1));
+        if target_count > 0 && per_target < vertex_count * 9 {
+            return Err(MeshConversionError::MorphTargetSize {
+                vertices: vertex_count,
+                floats: per_target,
+            });
+        }
+
+        for target in 0..target_count {
+            let mut bounds = Bounds::EMPTY;
+            for vertex in 0..vertex_count {
+                let i = target * layer + vertex * 9;
+                let delta = &floats[i..i + 9];
+                let position = Vec3::from_slice(&delta[0..3]);
+                bounds = bounds.grow(position);
+                self.morph_targets.push(MorphDelta {
+                    position,
+                    normal: Vec3::from_slice(&delta[3..6]),
+                    tangent: Vec3::from_slice(&delta[6..9]),
+                });
+            }
+            self.morph_bounds.push(bounds);
+        }
+        Ok(())
+    }
 }
 
-#[derive(Resource)]
+/// Every other triangle in a strip is flipped to keep the winding
+fn strip_to_list(strip: &[u32], restart: u32) -> Vec<u32> {
+    let mut list = Vec::with_capacity(strip.len().saturating_sub(2) * 3);
+    for strip in strip.split(|&i| i == restart) {
+        for (i, tri) in strip.windows(3).enumerate() {
+            if i % 2 == 0 {
+                list.extend_from_slice(tri);
+            } else {
+                list.extend_from_slice(&[tri[1], tri[0], tri[2]]);
+            }
+        }
+    }
+
+    list
+}
+
+/// Area weighted vertex normals, flat for meshes that don't share vertices
+fn smooth_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for tri in indices.chunks_exact(3) {
+        let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| positions[i as usize]);
+        let normal = (b - a).cross(c - a);
+        for &i in tri {
+            normals[i as usize] += normal;
+        }
+    }
+
+    normals.iter().map(|n| n.normalize_or_zero()).collect()
+}
+
+fn snorm8(v: i8) -> f32 {
+    (v as f32 / i8::MAX as f32).max(-1.0)
+}
+
+fn snorm16(v: i16) -> f32 {
+    (v as f32 / i16::MAX as f32).max(-1.0)
+}
+
+fn vec2_attribute(values: &VertexAttributeValues) -> Option<Vec<Vec2>> {
+    use VertexAttributeValues as V;
+    Some(match values {
+        V::Float32x2(v) => v.iter().map(|&v| Vec2::from_array(v)).collect(),
+        V::Unorm16x2(v) => v
+            .iter()
+            .map(|&[x, y]| Vec2::new(x as f32, y as f32) / u16::MAX as f32)
+            .collect(),
+        V::Unorm8x2(v) => v
+            .iter()
+            .map(|&[x, y]| Vec2::new(x as f32, y as f32) / u8::MAX as f32)
+            .collect(),
+        V::Snorm16x2(v) => v
+            .iter()
+            .map(|&[x, y]| Vec2::new(snorm16(x), snorm16(y)))
+            .collect(),
+        _ => return None,
+    })
+}
+
+fn vec3_attribute(values: &VertexAttributeValues) -> Option<Vec<Vec3>> {
+    match values {
+        VertexAttributeValues::Float32x3(v) => {
+            Some(v.iter().map(|&v| Vec3::from_array(v)).collect())
+        }
+        values => vec4_attribute(values).map(|v| v.into_iter().map(Vec4::truncate).collect()),
+    }
+}
+
+/// Three component tangents get glTF's default handedness of one in `w`
+fn tangent_attribute(values: &VertexAttributeValues) -> Option<Vec<Vec4>> {
+    match values {
+        VertexAttributeValues::Float32x3(v) => {
+            Some(v.iter().map(|&v| Vec3::from_array(v).extend(1.0)).collect())
+        }
+        values => vec4_attribute(values),
+    }
+}
+
+fn vec4_attribute(values: &VertexAttributeValues) -> Option<Vec<Vec4>> {
+    use VertexAttributeValues as V;
+    Some(match values {
+        V::Float32x4(v) => v.iter().map(|&v| Vec4::from_array(v)).collect(),
+        V::Snorm16x4(v) => v
+            .iter()
+            .map(|&v| Vec4::from_array(v.map(snorm16)))
+            .collect(),
+        V::Snorm8x4(v) => v.iter().map(|&v| Vec4::from_array(v.map(snorm8))).collect(),
+        _ => return None,
+    })
+}
+
+/// How textures reach the shader, picked from the device's features unless it's inserted
+/// before [`RayTracePlugin`](crate::RayTracePlugin) finishes
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayTraceTextureMode {
+    /// Bevy's `GpuImage`s bound as a texture binding array, sampled by the hardware
+    BindingArray,
+    /// Decoded into the texture data storage buffer and sampled in the shader
+    StorageBuffer,
+}
+
+#[derive(Resource, Default)]
 pub struct RayTraceMeta {
+    /// Incremented whenever any of the buffers is rewritten
+    pub generation: u64,
+
     pub objects: StorageBuffer<Vec<Object>>,
-    pub emissives: StorageBuffer<Vec<u32>>,
+    pub primitives: StorageBuffer<Vec<Primitive>>,
+    pub emissives: StorageBuffer<Vec<Emissive>>,
+    pub emissive_cdf: StorageBuffer<Vec<f32>>,
+    pub tlas: StorageBuffer<Vec<BvhNode>>,
+    pub lights: StorageBuffer<Vec<Light>>,
 
     pub meshes: StorageBuffer<Vec<GpuMesh>>,
     pub indices: StorageBuffer<Vec<u32>>,
     pub vertices: StorageBuffer<Vec<GpuVertex>>,
+    pub nodes: StorageBuffer<Vec<BvhNode>>,
+
+    pub skins: StorageBuffer<Vec<SkinVertex>>,
+    pub skin_instances: StorageBuffer<Vec<SkinInstance>>,
+    pub joints: StorageBuffer<Vec<Mat4>>,
+    pub morphs: StorageBuffer<Vec<MorphDelta>>,
+    pub morph_weights: StorageBuffer<Vec<f32>>,
 
     pub handle_to_material: HashMap<UntypedAssetId, usize>,
     pub handle_to_texture: HashMap<UntypedAssetId, usize>,
+    /// Textures whose index changed since the materials using them were last written
+    pub changed_textures: HashSet<UntypedAssetId>,
+    /// Materials using each texture, to resolve them again when its index changes
+    pub texture_to_materials: HashMap<UntypedAssetId, HashSet<AssetId<StandardMaterial>>>,
+    /// Image of every texture, bound in order with [`RayTraceTextureMode::BindingArray`]
+    pub texture_images: Vec<AssetId<Image>>,
     pub materials: StorageBuffer<Vec<Material>>,
+    /// Materials changed since they were last uploaded
+    pub modified_materials: Vec<usize>,
+    /// Whether materials were added or removed since the buffer was last written
+    pub materials_resized: bool,
     pub textures: StorageBuffer<Vec<Texture>>,
-    pub texture_data: StorageBuffer<Vec<f32>>,
+    pub texture_data: StorageBuffer<Vec<u32>>,
+    /// Textures replaced in place since they were last uploaded
+    pub modified_textures: Vec<usize>,
+    /// Parts of `texture_data` replaced in place since it was last uploaded
+    pub modified_texture_data: Vec<Range<usize>>,
+    /// Whether textures were added, removed or moved since the buffers were last written
+    pub textures_resized: bool,
+    /// Size of `texture_data`, zero when textures are bound as a binding array
+    pub texture_bytes: usize,
 }
 
 impl TextureData {
-    pub fn append_texture(&mut self, image: &Image) -> Texture {
+    /// Returns none when the image's format isn't supported or its data is too short
+    pub fn append_texture(&mut self, image: &Image) -> Option<Texture> {
         use bevy::render::render_resource::TextureFormat as WgpuTextureFormat;
-        const DIV_255: f32 = 1.0 / 255.0;
 
-        let offset = self.data.len() as u32;
-        let format = match image.texture_descriptor.format {
-            WgpuTextureFormat::Rgba8UnormSrgb => {
-                image
-                    .data
-                    .chunks(1)
-                    .for_each(|c| self.data.push(c[0] as f32 * DIV_255));
-                4
-            }
-            WgpuTextureFormat::Rgba16Float => {
-                image.data.chunks(2).for_each(|c| {
-                    self.data
-                        .push(f16::from_le_bytes([c[0], c[1]]) as f32 * DIV_255)
-                });
-                4
-            }
-            WgpuTextureFormat::Rgb9e5Ufloat => {
-                image.data.chunks(4).for_each(|d| {
-                    let e = (d[3] << 2) & 0b011111;
-                    let r = [e | (d[0] >> 7), (d[0] << 1) | ((d[1] & 0b1) >> 7)];
-                    let g = [e | ((d[1] & 0b01) >> 6), (d[1] << 2) | ((d[2] & 0b11) >> 6)];
-                    let b = [
-                        e | ((d[2] & 0b001) >> 5),
-                        (d[2] << 3) | ((d[3] & 0b111) >> 5),
-                    ];
-
-                    self.data.push(f16::from_le_bytes(r) as f32);
-                    self.data.push(f16::from_le_bytes(g) as f32);
-                    self.data.push(f16::from_le_bytes(b) as f32);
-                });
-                3
+        let mut texture = Texture::from_image(image);
+        if texture.format == 0 {
+            return None;
+        }
+        texture.offset = self.data.len() as u32;
+        let format = texture.format;
+
+        // Only the first layer's base level is kept, the mips are regenerated below
+        let len = (texture.width * texture.height * Texture::texel_size(format)) as usize;
+        #[cfg(feature = "compressed_textures")]
+        let data = if image.texture_descriptor.format.is_compressed() {
+            crate::compressed::decompress(
+                image.texture_descriptor.format,
+                texture.width,
+                texture.height,
+                &image.data,
+            )
+            .map(std::borrow::Cow::Owned)
+        } else {
+            Some(std::borrow::Cow::Borrowed(&image.data[..]))
+        };
+        #[cfg(not(feature = "compressed_textures"))]
+        let data = Some(&image.data[..]);
+        let Some(bytes) = data.as_ref().and_then(|data| data.get(..len)) else {
+            warn!(
+                "A {}x{} {:?} image has only {} bytes of data and will be skipped",
+                texture.width,
+                texture.height,
+                image.texture_descriptor.format,
+                image.data.len()
+            );
+            return None;
+        };
+        let bgra = matches!(
+            image.texture_descriptor.format,
+            WgpuTextureFormat::Bgra8UnormSrgb | WgpuTextureFormat::Bgra8Unorm
+        );
+        self.data.extend(bytes.chunks(4).map(|c| {
+            let mut word = [0; 4];
+            word[..c.len()].copy_from_slice(c);
+            if bgra {
+                word.swap(0, 2);
             }
-            WgpuTextureFormat::R8Unorm => {
-                image.data.chunks(1).for_each(|r| {
-                    self.data.push(r[0] as f32 * DIV_255);
-                });
-                1
+            u32::from_le_bytes(word)
+        }));
+
+        while texture.width >> texture.mip_levels > 0 || texture.height >> texture.mip_levels > 0 {
+            self.append_mip(&texture);
+            texture.mip_levels += 1;
+        }
+
+        Some(texture)
+    }
+
+    /// Box filters the last level of `texture` into a new one half its size
+    fn append_mip(&mut self, texture: &Texture) {
+        let level = texture.mip_levels - 1;
+        let (width, height) = texture.level_size(level);
+        let (next_width, next_height) = texture.level_size(level + 1);
+
+        let mut texels = Vec::with_capacity((next_width * next_height) as usize);
+        for y in 0..next_height as i32 {
+            for x in 0..next_width as i32 {
+                // Odd sizes repeat the last row or column
+                let xs = [
+                    (x * 2).min(width as i32 - 1),
+                    (x * 2 + 1).min(width as i32 - 1),
+                ];
+                let ys = [
+                    (y * 2).min(height as i32 - 1),
+                    (y * 2 + 1).min(height as i32 - 1),
+                ];
+                let mut sum = Vec4::ZERO;
+                for y in ys {
+                    for x in xs {
+                        sum += self.load_texel(texture, level, x, y);
+                    }
+                }
+                texels.push(sum * 0.25);
             }
-            WgpuTextureFormat::Rg8Unorm => {
-                image.data.chunks(1).for_each(|r| {
-                    self.data.push(r[0] as f32 * DIV_255);
-                });
-                2
+        }
+
+        self.push_texels(texture.format, &texels);
+    }
+
+    fn push_texels(&mut self, format: u32, texels: &[Vec4]) {
+        let unorm8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u32;
+        let half = |v: f32| (v as f16).to_bits() as u32;
+
+        match format {
+            Texture::FORMAT_R8 => self.data.extend(texels.chunks(4).map(|c| {
+                c.iter()
+                    .enumerate()
+                    .fold(0, |word, (i, t)| word | unorm8(t.x) << (i * 8))
+            })),
+            Texture::FORMAT_RG8 => self.data.extend(texels.chunks(2).map(|c| {
+                c.iter().enumerate().fold(0, |word, (i, t)| {
+                    word | (unorm8(t.x) | unorm8(t.y) << 8) << (i * 16)
+                })
+            })),
+            Texture::FORMAT_RGBA8 => {
+                self.data.extend(texels.iter().map(|t| {
+                    unorm8(t.x) | unorm8(t.y) << 8 | unorm8(t.z) << 16 | unorm8(t.w) << 24
+                }))
             }
-            f => {
-                panic!("Texture format {:?} is not supported.", f);
+            Texture::FORMAT_RGBA8_SRGB => self.data.extend(texels.iter().map(|t| {
+                let [r, g, b] = t.truncate().to_array().map(Srgba::gamma_function_inverse);
+                unorm8(r) | unorm8(g) << 8 | unorm8(b) << 16 | unorm8(t.w) << 24
+            })),
+            Texture::FORMAT_RGBA16F => self.data.extend(
+                texels
+                    .iter()
+                    .flat_map(|t| [half(t.x) | half(t.y) << 16, half(t.z) | half(t.w) << 16]),
+            ),
+            Texture::FORMAT_RGB9E5 => self
+                .data
+                .extend(texels.iter().map(|t| encode_rgb9e5(t.truncate()))),
+            Texture::FORMAT_RGBA32F => self
+                .data
+                .extend(texels.iter().flat_map(|t| t.to_array().map(f32::to_bits))),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Mirror of `sample_texture` in `raytrace.wgsl`, changes have to be made to both
+    pub fn sample(&self, texture: &Texture, uv: Vec2, footprint: f32) -> Vec3 {
+        if texture.format == 0 || texture.format > Texture::FORMAT_RGBA8_SRGB {
+            return Vec3::ONE;
+        }
+
+        let lod = (footprint * ((texture.width * texture.height) as f32).sqrt()).log2();
+        let color = if lod <= 0.0 || texture.mip_levels == 1 {
+            let linear = texture.filter_mode & Texture::FILTER_MAG_LINEAR != 0;
+            self.sample_level(texture, uv, 0, linear)
+        } else {
+            let linear = texture.filter_mode & Texture::FILTER_MIN_LINEAR != 0;
+            let level = lod.min((texture.mip_levels - 1) as f32);
+            if texture.filter_mode & Texture::FILTER_MIPMAP_LINEAR != 0 {
+                let lower = level.floor() as u32;
+                let upper = (lower + 1).min(texture.mip_levels - 1);
+                self.sample_level(texture, uv, lower, linear)
+                    .lerp(self.sample_level(texture, uv, upper, linear), level.fract())
+            } else {
+                self.sample_level(texture, uv, level.round() as u32, linear)
             }
         };
 
+        color.truncate() * color.w
+    }
+
+    fn sample_level(&self, texture: &Texture, uv: Vec2, level: u32, linear: bool) -> Vec4 {
+        let (width, height) = texture.level_size(level);
+        let texel = uv * Vec2::new(width as f32, height as f32);
+        if !linear {
+            let texel = texel.floor();
+            return self.load_texel(texture, level, texel.x as i32, texel.y as i32);
+        }
+
+        // Bilinear between the four closest texel centers
+        let p = texel - 0.5;
+        let (x, y) = (p.x.floor() as i32, p.y.floor() as i32);
+        let f = p - p.floor();
+        let a = self.load_texel(texture, level, x, y);
+        let b = self.load_texel(texture, level, x + 1, y);
+        let c = self.load_texel(texture, level, x, y + 1);
+        let d = self.load_texel(texture, level, x + 1, y + 1);
+        a.lerp(b, f.x).lerp(c.lerp(d, f.x), f.y)
+    }
+
+    /// Texel `x`, `y` of a mip level after wrapping, alpha is one for formats without it
+    pub fn load_texel(&self, texture: &Texture, level: u32, x: i32, y: i32) -> Vec4 {
+        let (width, height) = texture.level_size(level);
+        let x = address(x, width as i32, texture.address_mode_u);
+        let y = address(y, height as i32, texture.address_mode_v);
+        let t = (x + y * width) as usize;
+        let offset = texture.level_offset(level) as usize;
+
+        let d = &self.data;
+        let unorm8 = |word: u32, byte: usize| ((word >> (byte * 8)) & 0xff) as f32 / 255.0;
+        let half = |word: u32, i: usize| f16::from_bits((word >> (i * 16)) as u16) as f32;
+        match texture.format {
+            Texture::FORMAT_R8 => Vec3::splat(unorm8(d[offset + t / 4], t % 4)).extend(1.0),
+            Texture::FORMAT_RG8 => {
+                let word = d[offset + t / 2] >> ((t % 2) * 16);
+                Vec4::new(unorm8(word, 0), unorm8(word, 1), 0.0, 1.0)
+            }
+            Texture::FORMAT_RGBA8 => {
+                let word = d[offset + t];
+                Vec4::from_array([0, 1, 2, 3].map(|byte| unorm8(word, byte)))
+            }
+            Texture::FORMAT_RGBA8_SRGB => {
+                let word = d[offset + t];
+                let color = [0, 1, 2].map(|byte| Srgba::gamma_function(unorm8(word, byte)));
+                Vec3::from_array(color).extend(unorm8(word, 3))
+            }
+            Texture::FORMAT_RGBA16F => {
+                let (a, b) = (d[offset + t * 2], d[offset + t * 2 + 1]);
+                Vec4::new(half(a, 0), half(a, 1), half(b, 0), half(b, 1))
+            }
+            Texture::FORMAT_RGB9E5 => decode_rgb9e5(d[offset + t]).extend(1.0),
+            _ => Vec4::from_array([0, 1, 2, 3].map(|c| f32::from_bits(d[offset + t * 4 + c]))),
+        }
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.data.len() * size_of::<u32>()
+    }
+}
+
+impl Texture {
+    /// Size, format and sampler of `image`, the format is zero when it can't be stored in [`TextureData`]
+    pub fn from_image(image: &Image) -> Self {
+        use bevy::render::render_resource::TextureFormat as WgpuTextureFormat;
+
+        let format = match image.texture_descriptor.format {
+            // Blue and red are swapped when appended
+            WgpuTextureFormat::Rgba8Unorm | WgpuTextureFormat::Bgra8Unorm => Texture::FORMAT_RGBA8,
+            WgpuTextureFormat::Rgba8UnormSrgb | WgpuTextureFormat::Bgra8UnormSrgb => {
+                Texture::FORMAT_RGBA8_SRGB
+            }
+            WgpuTextureFormat::Rgba16Float => Texture::FORMAT_RGBA16F,
+            WgpuTextureFormat::Rgba32Float => Texture::FORMAT_RGBA32F,
+            WgpuTextureFormat::Rgb9e5Ufloat => Texture::FORMAT_RGB9E5,
+            WgpuTextureFormat::R8Unorm => Texture::FORMAT_R8,
+            WgpuTextureFormat::Rg8Unorm => Texture::FORMAT_RG8,
+            #[cfg(feature = "compressed_textures")]
+            format => crate::compressed::decompressed_format(format).unwrap_or(0),
+            #[cfg(not(feature = "compressed_textures"))]
+            _ => 0,
+        };
+
+        // `ImagePlugin`'s default sampler can't be read back, assume it's the default linear one
+        let sampler = match &image.sampler {
+            ImageSampler::Default => ImageSamplerDescriptor::linear(),
+            ImageSampler::Descriptor(descriptor) => descriptor.clone(),
+        };
+        let address_mode = |mode| match mode {
+            ImageAddressMode::Repeat => Texture::ADDRESS_REPEAT,
+            ImageAddressMode::MirrorRepeat => Texture::ADDRESS_MIRROR,
+            ImageAddressMode::ClampToEdge | ImageAddressMode::ClampToBorder => {
+                Texture::ADDRESS_CLAMP
+            }
+        };
+        let filter = |mode, flag| match mode {
+            ImageFilterMode::Linear => flag,
+            ImageFilterMode::Nearest => 0,
+        };
+
         Texture {
             width: image.width(),
             height: image.height(),
-            offset,
+            offset: 0,
             format,
+            address_mode_u: address_mode(sampler.address_mode_u),
+            address_mode_v: address_mode(sampler.address_mode_v),
+            filter_mode: filter(sampler.mag_filter, Texture::FILTER_MAG_LINEAR)
+                | filter(sampler.min_filter, Texture::FILTER_MIN_LINEAR)
+                | filter(sampler.mipmap_filter, Texture::FILTER_MIPMAP_LINEAR),
+            mip_levels: 1,
+        }
+    }
+
+    pub fn level_size(&self, level: u32) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    pub fn level_offset(&self, level: u32) -> u32 {
+        (0..level).fold(self.offset, |offset, level| {
+            let (width, height) = self.level_size(level);
+            offset + Texture::words(self.format, width * height)
+        })
+    }
+
+    /// Bytes per texel of `format`
+    pub fn texel_size(format: u32) -> u32 {
+        match format {
+            Texture::FORMAT_R8 => 1,
+            Texture::FORMAT_RG8 => 2,
+            Texture::FORMAT_RGBA16F => 8,
+            Texture::FORMAT_RGBA32F => 16,
+            _ => 4,
+        }
+    }
+
+    /// Words taken by `texels` texels of `format`, every level starts on a new word
+    pub fn words(format: u32, texels: u32) -> u32 {
+        (texels * Texture::texel_size(format)).div_ceil(4)
+    }
+}
+
+// Shared exponent float, 9 bit mantissas with a bias of 15
+fn decode_rgb9e5(word: u32) -> Vec3 {
+    let exponent = (word >> 27) as i32 - 15 - 9;
+    let mantissa = Vec3::new(
+        (word & 0x1ff) as f32,
+        ((word >> 9) & 0x1ff) as f32,
+        ((word >> 18) & 0x1ff) as f32,
+    );
+    mantissa * 2f32.powi(exponent)
+}
+
+fn encode_rgb9e5(color: Vec3) -> u32 {
+    const MAX: f32 = 511.0 / 512.0 * 65536.0;
+    let color = color.clamp(Vec3::ZERO, Vec3::splat(MAX));
+
+    let max = color.max_element();
+    let mut exponent = max.log2().floor().max(-16.0) as i32 + 1 + 15;
+    if (max / 2f32.powi(exponent - 15 - 9) + 0.5).floor() >= 512.0 {
+        exponent += 1;
+    }
+
+    let mantissa = (color / 2f32.powi(exponent - 15 - 9) + 0.5).floor();
+    mantissa.x as u32
+        | (mantissa.y as u32) << 9
+        | (mantissa.z as u32) << 18
+        | (exponent as u32) << 27
+}
+
+// Wraps a texel coordinate into `0..size`
+fn address(i: i32, size: i32, mode: u32) -> u32 {
+    match mode {
+        Texture::ADDRESS_REPEAT => i.rem_euclid(size) as u32,
+        Texture::ADDRESS_MIRROR => {
+            let m = i.rem_euclid(size * 2);
+            (if m >= size { size * 2 - 1 - m } else { m }) as u32
+        }
+        _ => i.clamp(0, size - 1) as u32,
+    }
+}
+
+/// Uploads `range` of the elements in `buffer` without rewriting the rest of it,
+/// the buffer has to be written in full first
+pub fn write_buffer_range<T: ShaderType + ShaderSize + WriteInto>(
+    buffer: &StorageBuffer<Vec<T>>,
+    range: Range<usize>,
+    render_queue: &RenderQueue,
+) {
+    let Some(gpu_buffer) = buffer.buffer() else {
+        return;
+    };
+
+    let offset = range.start as u64 * T::SHADER_SIZE.get();
+    let mut bytes = encase::StorageBuffer::new(Vec::new());
+    bytes.write(&buffer.get()[range]).unwrap();
+    render_queue.write_buffer(gpu_buffer, offset, bytes.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::RenderAssetUsages,
+        color::Color,
+        render::{
+            mesh::morph::{MorphAttributes, MorphTargetImage},
+            render_resource::{Extent3d, TextureDimension, TextureFormat},
+        },
+    };
+
+    use super::*;
+
+    fn mesh(topology: PrimitiveTopology, vertex_count: usize, indices: Option<Indices>) -> Mesh {
+        // A zig zag in the XY plane, so consecutive triangles share an edge like a strip
+        let positions: Vec<[f32; 3]> = (0..vertex_count)
+            .map(|i| [(i / 2) as f32, (i % 2) as f32, 0.0])
+            .collect();
+        let mut mesh = Mesh::new(topology, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        if let Some(indices) = indices {
+            mesh.insert_indices(indices);
+        }
+        mesh
+    }
+
+    /// Triangles in a stable order, the hierarchy reorders them
+    fn triangles(mesh: &CpuMesh) -> Vec<[u32; 3]> {
+        let mut triangles: Vec<[u32; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|tri| [tri[0], tri[1], tri[2]])
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    fn winding(mesh: &CpuMesh, [a, b, c]: [u32; 3]) -> f32 {
+        let [a, b, c] = [a, b, c].map(|i| mesh.vertices[i as usize].position);
+        (b - a).cross(c - a).z
+    }
+
+    #[test]
+    fn triangle_list() {
+        let cpu = CpuMesh::from_mesh(&mesh(
+            PrimitiveTopology::TriangleList,
+            4,
+            Some(Indices::U32(vec![0, 2, 1, 1, 2, 3, 0])),
+        ))
+        .unwrap();
+        // The incomplete last triangle is dropped
+        assert_eq!(triangles(&cpu), [[0, 2, 1], [1, 2, 3]]);
+    }
+
+    #[test]
+    fn triangle_strip_alternates_winding() {
+        let cpu = CpuMesh::from_mesh(&mesh(
+            PrimitiveTopology::TriangleStrip,
+            6,
+            Some(Indices::U16(vec![0, 1, 2, 3, 4, 5])),
+        ))
+        .unwrap();
+        assert_eq!(
+            triangles(&cpu),
+            [[0, 1, 2], [2, 1, 3], [2, 3, 4], [4, 3, 5]]
+        );
+
+        // Every other triangle is flipped back so they all face the same way
+        let first = winding(&cpu, [0, 1, 2]).signum();
+        for tri in triangles(&cpu) {
+            assert_eq!(winding(&cpu, tri).signum(), first);
+        }
+    }
+
+    #[test]
+    fn triangle_strip_restart() {
+        let expected = [[0, 1, 2], [2, 1, 3], [4, 5, 6]];
+
+        let cpu = CpuMesh::from_mesh(&mesh(
+            PrimitiveTopology::TriangleStrip,
+            7,
+            Some(Indices::U16(vec![0, 1, 2, 3, u16::MAX, 4, 5, 6])),
+        ))
+        .unwrap();
+        assert_eq!(triangles(&cpu), expected);
+
+        let cpu = CpuMesh::from_mesh(&mesh(
+            PrimitiveTopology::TriangleStrip,
+            7,
+            Some(Indices::U32(vec![0, 1, 2, 3, u32::MAX, 4, 5, 6])),
+        ))
+        .unwrap();
+        assert_eq!(triangles(&cpu), expected);
+    }
+
+    #[test]
+    fn non_indexed_meshes_get_indices() {
+        let cpu = CpuMesh::from_mesh(&mesh(PrimitiveTopology::TriangleList, 6, None)).unwrap();
+        assert_eq!(triangles(&cpu), [[0, 1, 2], [3, 4, 5]]);
+
+        let cpu = CpuMesh::from_mesh(&mesh(PrimitiveTopology::TriangleStrip, 4, None)).unwrap();
+        assert_eq!(triangles(&cpu), [[0, 1, 2], [2, 1, 3]]);
+    }
+
+    #[test]
+    fn three_component_tangents_are_right_handed() {
+        let tangents = VertexAttributeValues::Float32x3(vec![[1.0, 0.0, 0.0]]);
+        assert_eq!(
+            tangent_attribute(&tangents),
+            Some(vec![Vec4::new(1.0, 0.0, 0.0, 1.0)])
+        );
+
+        // Positions and normals only take the first three components
+        assert_eq!(vec3_attribute(&tangents), Some(vec![Vec3::X]));
+        let normals = VertexAttributeValues::Float32x4(vec![[0.0, 1.0, 0.0, 0.0]]);
+        assert_eq!(vec3_attribute(&normals), Some(vec![Vec3::Y]));
+    }
+
+    #[test]
+    fn lines_and_points_are_unsupported() {
+        for topology in [
+            PrimitiveTopology::PointList,
+            PrimitiveTopology::LineList,
+            PrimitiveTopology::LineStrip,
+        ] {
+            let result = CpuMesh::from_mesh(&mesh(topology, 4, None));
+            assert!(
+                matches!(result, Err(MeshConversionError::UnsupportedTopology(t)) if t == topology),
+                "{topology:?}"
+            );
+        }
+    }
+
+    fn morph_image(vertex_count: usize, targets: usize) -> Image {
+        // Target t moves vertex v by (v, t, 1) and leaves its normal and tangent alone
+        let targets = (0..targets).map(|t| {
+            (0..vertex_count).map(move |v| MorphAttributes {
+                position: Vec3::new(v as f32, t as f32, 1.0),
+                ..Default::default()
+            })
+        });
+        MorphTargetImage::new(targets, vertex_count, RenderAssetUsages::default())
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn morph_targets() {
+        let mut cpu = CpuMesh::from_mesh(&mesh(PrimitiveTopology::TriangleList, 6, None)).unwrap();
+        cpu.set_morph_targets(&morph_image(6, 3)).unwrap();
+
+        assert_eq!(cpu.morph_targets.len(), 18);
+        for (i, delta) in cpu.morph_targets.iter().enumerate() {
+            let (t, v) = (i / 6, i % 6);
+            assert_eq!(delta.position, Vec3::new(v as f32, t as f32, 1.0));
+            assert_eq!(delta.normal, Vec3::ZERO);
+        }
+        assert_eq!(
+            cpu.morph_bounds[2],
+            Bounds {
+                min: Vec3::new(0.0, 2.0, 1.0),
+                max: Vec3::new(5.0, 2.0, 1.0),
+            }
+        );
+    }
+
+    #[test]
+    fn morph_targets_of_another_mesh_are_rejected() {
+        let mut cpu = CpuMesh::from_mesh(&mesh(PrimitiveTopology::TriangleList, 6, None)).unwrap();
+        cpu.set_morph_targets(&morph_image(6, 2)).unwrap();
+
+        let result = cpu.set_morph_targets(&morph_image(5, 2));
+        assert!(matches!(
+            result,
+            Err(MeshConversionError::MorphTargetSize {
+                vertices: 6,
+                floats: 45
+            })
+        ));
+        assert!(cpu.morph_targets.is_empty() && cpu.morph_bounds.is_empty());
+    }
+
+    const FORMATS: [TextureFormat; 8] = [
+        TextureFormat::R8Unorm,
+        TextureFormat::Rg8Unorm,
+        TextureFormat::Rgba8Unorm,
+        TextureFormat::Bgra8Unorm,
+        TextureFormat::Rgba8UnormSrgb,
+        TextureFormat::Rgba16Float,
+        TextureFormat::Rgba32Float,
+        TextureFormat::Rgb9e5Ufloat,
+    ];
+
+    /// Bytes of a distinct texel at `x`, `y` and the color it should load as
+    fn texel(format: TextureFormat, x: u32, y: u32) -> (Vec<u8>, Vec4) {
+        let [r, g, b, a] = [
+            x * 37 + y * 11,
+            x * 5 + y * 71,
+            x * 13 + y * 29,
+            255 - x * 3 - y,
+        ]
+        .map(|c| (c % 256) as u8);
+        let unorm = |c: u8| c as f32 / 255.0;
+        let float = Vec4::new(r as f32, g as f32, b as f32, a as f32) / 64.0;
+        match format {
+            TextureFormat::R8Unorm => (vec![r], Vec3::splat(unorm(r)).extend(1.0)),
+            TextureFormat::Rg8Unorm => (vec![r, g], Vec4::new(unorm(r), unorm(g), 0.0, 1.0)),
+            TextureFormat::Rgba8Unorm => (vec![r, g, b, a], [r, g, b, a].map(unorm).into()),
+            TextureFormat::Bgra8Unorm => (vec![b, g, r, a], [r, g, b, a].map(unorm).into()),
+            TextureFormat::Rgba8UnormSrgb => (
+                vec![r, g, b, a],
+                Vec3::from_array([r, g, b].map(|c| Srgba::gamma_function(unorm(c))))
+                    .extend(unorm(a)),
+            ),
+            TextureFormat::Rgba16Float => (
+                float
+                    .to_array()
+                    .iter()
+                    .flat_map(|&c| (c as f16).to_le_bytes())
+                    .collect(),
+                float,
+            ),
+            TextureFormat::Rgba32Float => (
+                float
+                    .to_array()
+                    .iter()
+                    .flat_map(|c| c.to_le_bytes())
+                    .collect(),
+                float,
+            ),
+            TextureFormat::Rgb9e5Ufloat => {
+                let word = encode_rgb9e5(float.truncate());
+                (word.to_le_bytes().to_vec(), decode_rgb9e5(word).extend(1.0))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn image(format: TextureFormat, width: u32, height: u32) -> (Image, Vec<Vec4>) {
+        let mut data = Vec::new();
+        let mut expected = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let (bytes, color) = texel(format, x, y);
+                data.extend(bytes);
+                expected.push(color);
+            }
+        }
+
+        let mut image = Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            format,
+            RenderAssetUsages::default(),
+        );
+        image.sampler = ImageSampler::nearest();
+        (image, expected)
+    }
+
+    fn assert_near(a: Vec4, b: Vec4, tolerance: f32, context: &str) {
+        assert!(
+            (a - b).abs().max_element() <= tolerance,
+            "{context}: {a} != {b}"
+        );
+    }
+
+    #[test]
+    fn textures_round_trip() {
+        for format in FORMATS {
+            for (width, height) in [(4, 4), (5, 3), (8, 2), (3, 7), (1, 1)] {
+                let context = format!("{format:?} {width}x{height}");
+                let (image, expected) = image(format, width, height);
+
+                // Something before it, so offsets are exercised too
+                let mut data = TextureData {
+                    data: vec![u32::MAX; 3],
+                };
+                let texture = data.append_texture(&image).unwrap();
+                assert_eq!(texture.offset, 3, "{context}");
+                assert_eq!(texture.mip_levels, width.max(height).ilog2() + 1);
+                assert_eq!(
+                    data.data.len() as u32,
+                    texture.level_offset(texture.mip_levels),
+                    "{context}"
+                );
+
+                for y in 0..height {
+                    for x in 0..width {
+                        let color = expected[(x + y * width) as usize];
+                        let context = format!("{context} texel {x}, {y}");
+                        let loaded = data.load_texel(&texture, 0, x as i32, y as i32);
+                        assert_near(loaded, color, 1e-6, &context);
+
+                        let uv = (Vec2::new(x as f32, y as f32) + 0.5)
+                            / Vec2::new(width as f32, height as f32);
+                        let sampled = data.sample(&texture, uv, 0.0);
+                        assert_near(
+                            sampled.extend(0.0),
+                            (color.truncate() * color.w).extend(0.0),
+                            1e-6,
+                            &context,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mips_round_trip() {
+        for format in FORMATS {
+            // Non-square, so a row stride of the height instead of the width shows up
+            let (width, height) = (8, 2);
+            let (image, _) = image(format, width, height);
+            let mut data = TextureData::default();
+            let texture = data.append_texture(&image).unwrap();
+
+            for level in 1..texture.mip_levels {
+                let (level_width, level_height) = texture.level_size(level);
+                let (last_width, last_height) = texture.level_size(level - 1);
+                for y in 0..level_height as i32 {
+                    for x in 0..level_width as i32 {
+                        let mut average = Vec4::ZERO;
+                        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                            let sx = (x * 2 + dx).min(last_width as i32 - 1);
+                            let sy = (y * 2 + dy).min(last_height as i32 - 1);
+                            average += data.load_texel(&texture, level - 1, sx, sy) * 0.25;
+                        }
+
+                        let context = format!("{format:?} level {level} texel {x}, {y}");
+                        let loaded = data.load_texel(&texture, level, x, y);
+                        let tolerance = 0.02 * average.abs().max_element().max(1.0);
+                        assert_near(loaded, average, tolerance, &context);
+                    }
+                }
+
+                // A footprint of one texel of the level picks it
+                let footprint = 2f32.powi(level as i32) / (width as f32 * height as f32).sqrt();
+                let loaded = data.load_texel(&texture, level, 0, 0);
+                let sampled = data.sample(&texture, Vec2::splat(0.01), footprint);
+                assert_near(
+                    sampled.extend(0.0),
+                    (loaded.truncate() * loaded.w).extend(0.0),
+                    1e-6,
+                    &format!("{format:?} sampling level {level}"),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_skipped() {
+        for format in FORMATS {
+            let (mut image, _) = image(format, 5, 3);
+            image.data.truncate(image.data.len() - 1);
+            let mut data = TextureData::default();
+            assert!(data.append_texture(&image).is_none(), "{format:?}");
+            assert!(data.data.is_empty());
+        }
+    }
+
+    #[test]
+    fn known_texels() {
+        let rgb9e5 = |r: u32, g: u32, b: u32, exponent: u32| {
+            (r | g << 9 | b << 18 | exponent << 27)
+                .to_le_bytes()
+                .to_vec()
+        };
+        let halves = |bits: [u16; 4]| bits.iter().flat_map(|b| b.to_le_bytes()).collect();
+        let floats = |v: [f32; 4]| v.iter().flat_map(|f| f.to_le_bytes()).collect();
+
+        let table: [(TextureFormat, Vec<u8>, Vec4); 8] = [
+            // 9 bit mantissas scaled by 2^(exponent - 15 - 9)
+            (
+                TextureFormat::Rgb9e5Ufloat,
+                rgb9e5(256, 511, 0, 15),
+                Vec4::new(0.5, 511.0 / 512.0, 0.0, 1.0),
+            ),
+            (
+                TextureFormat::Rgb9e5Ufloat,
+                rgb9e5(128, 64, 1, 17),
+                Vec4::new(1.0, 0.5, 1.0 / 128.0, 1.0),
+            ),
+            (
+                TextureFormat::Rgb9e5Ufloat,
+                rgb9e5(1, 0, 0, 0),
+                Vec4::new(2f32.powi(-24), 0.0, 0.0, 1.0),
+            ),
+            (
+                TextureFormat::Rgba16Float,
+                halves([0x3c00, 0x3800, 0xc000, 0x7bff]),
+                Vec4::new(1.0, 0.5, -2.0, 65504.0),
+            ),
+            (
+                TextureFormat::Rgba32Float,
+                floats([1.5, -0.25, 1e-3, 1e6]),
+                Vec4::new(1.5, -0.25, 1e-3, 1e6),
+            ),
+            // Blue and red are swapped into RGBA order
+            (
+                TextureFormat::Bgra8Unorm,
+                vec![0x10, 0x80, 0xff, 0x40],
+                Vec4::new(1.0, 128.0 / 255.0, 16.0 / 255.0, 64.0 / 255.0),
+            ),
+            (
+                TextureFormat::Bgra8UnormSrgb,
+                vec![0x00, 0xff, 0xbc, 0xff],
+                Vec4::new(Srgba::gamma_function(188.0 / 255.0), 1.0, 0.0, 1.0),
+            ),
+            (
+                TextureFormat::Rgba8Unorm,
+                vec![0x10, 0x80, 0xff, 0x40],
+                Vec4::new(16.0 / 255.0, 128.0 / 255.0, 1.0, 64.0 / 255.0),
+            ),
+        ];
+
+        for (format, bytes, expected) in table {
+            let image = Image::new(
+                Extent3d::default(),
+                TextureDimension::D2,
+                bytes,
+                format,
+                RenderAssetUsages::default(),
+            );
+            let mut data = TextureData::default();
+            let texture = data.append_texture(&image).unwrap();
+            let loaded = data.load_texel(&texture, 0, 0, 0);
+            assert_near(loaded, expected, 1e-7, &format!("{format:?}"));
+
+            // Mips are encoded again, which has to give back the same color
+            if format == TextureFormat::Rgb9e5Ufloat {
+                let encoded = decode_rgb9e5(encode_rgb9e5(expected.truncate()));
+                assert_near(encoded.extend(1.0), expected, 0.0, "RGB9E5 encoding");
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_matches_bevy() {
+        // Transcription of `srgb_to_linear` in `raytrace.wgsl`
+        let shader = |c: f32| {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        let bytes: Vec<u8> = (0..=255).flat_map(|v| [v, v, v, 255]).collect();
+        let image = Image::new(
+            Extent3d {
+                width: 256,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            bytes,
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::default(),
+        );
+        let mut data = TextureData::default();
+        let texture = data.append_texture(&image).unwrap();
+
+        for v in 0..=255u8 {
+            let expected = Color::srgb_u8(v, v, v).to_linear().red;
+            assert_eq!(expected, LinearRgba::from(Srgba::rgb_u8(v, v, v)).red);
+
+            let loaded = data.load_texel(&texture, 0, v as i32, 0);
+            assert!((loaded.x - expected).abs() <= 1e-6, "{v}: {loaded}");
+            assert!((shader(v as f32 / 255.0) - expected).abs() <= 1e-6, "{v}");
         }
     }
 }
@@ -1,101 +1,206 @@
-use crate::data::{self, CpuMesh, GpuMesh, RayTraceMeta, TextureData};
+use crate::{
+    alloc::RangeAllocator,
+    bvh::{self, Bounds},
+    data::{
+        self, BvhNode, CpuMesh, GpuMesh, RayTraceMeta, RayTracePrimitive, RayTraceTextureMode,
+        Texture, TextureData,
+    },
+    shader,
+    skinning::SkinnedMeshes,
+};
 use bevy::{
     prelude::*,
     render::{
-        mesh::VertexAttributeValues,
+        render_resource::{
+            encase::{internal::WriteInto, ShaderSize},
+            ShaderType, StorageBuffer, TextureFormat,
+        },
         renderer::{RenderDevice, RenderQueue},
         Extract,
     },
-    utils::HashMap,
+    utils::{HashMap, HashSet},
 };
+use std::{f32::consts::PI, ops::Range};
+
+/// Vertex, index and node ranges of a mesh in the gpu buffers
+pub struct MeshAllocation {
+    pub vertices: Range<u32>,
+    pub indices: Range<u32>,
+    pub nodes: Range<u32>,
+    /// Empty unless the mesh is skinned
+    pub skins: Range<u32>,
+    /// Empty unless the mesh has morph targets
+    pub morphs: Range<u32>,
+}
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct ProcessedMeshes {
-    pub meshes: Vec<CpuMesh>,
+    /// Removed meshes leave an empty slot which is reused by the next one,
+    /// so the index of every other mesh stays the same
+    pub meshes: Vec<Option<CpuMesh>>,
     pub asset_to_index: HashMap<AssetId<Mesh>, usize>,
-    pub changed: bool,
+    /// Meshes using each morph target image, to convert them again when it changes
+    morph_images: HashMap<AssetId<Image>, HashSet<AssetId<Mesh>>>,
+    free_slots: Vec<usize>,
+    /// Slots which changed since they were last uploaded
+    dirty: Vec<usize>,
+
+    pub allocations: Vec<Option<MeshAllocation>>,
+    vertex_allocator: RangeAllocator,
+    index_allocator: RangeAllocator,
+    node_allocator: RangeAllocator,
+    skin_allocator: RangeAllocator,
+    morph_allocator: RangeAllocator,
+}
+
+impl ProcessedMeshes {
+    /// Puts `mesh` in a free slot, it's uploaded by [`prepare_meshes`]
+    pub fn insert(&mut self, mesh: CpuMesh) -> usize {
+        let index = match self.free_slots.pop() {
+            Some(index) => {
+                self.meshes[index] = Some(mesh);
+                index
+            }
+            None => {
+                self.meshes.push(Some(mesh));
+                self.meshes.len() - 1
+            }
+        };
+        self.dirty.push(index);
+        index
+    }
+
+    pub fn replace(&mut self, index: usize, mesh: CpuMesh) {
+        self.meshes[index] = Some(mesh);
+        self.dirty.push(index);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.meshes[index] = None;
+        self.free_slots.push(index);
+        self.dirty.push(index);
+    }
+
+    /// Whether the slot changed since it was last uploaded
+    pub fn is_dirty(&self, index: usize) -> bool {
+        self.dirty.contains(&index)
+    }
 }
 
 pub fn extract_meshes(
     mesh_assets: Extract<Res<Assets<Mesh>>>,
+    image_assets: Extract<Res<Assets<Image>>>,
     mut asset_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
     mut processed_meshes: ResMut<ProcessedMeshes>,
 ) {
-    let mut remove = Vec::new();
-    let mut extract = Vec::new();
-
+    // Only the last event of every mesh matters, a mesh modified several times
+    // in one frame is converted once
+    let mut removed = HashMap::new();
     for event in asset_events.read() {
         match event {
             AssetEvent::Added { id }
             | AssetEvent::Modified { id }
             | AssetEvent::LoadedWithDependencies { id } => {
-                extract.push(*id);
+                removed.insert(*id, false);
             }
             AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
-                remove.push(*id);
+                removed.insert(*id, true);
             }
         }
-
-        processed_meshes.changed = true;
     }
 
-    for id in remove {
-        let index = processed_meshes.asset_to_index.remove(&id).unwrap();
-        processed_meshes.meshes.remove(index);
-
-        for ati in processed_meshes.asset_to_index.values_mut() {
-            if *ati < index {
-                continue;
-            }
-
-            *ati -= 1;
+    // Meshes whose morph target image loaded, changed or was dropped are converted again
+    for event in image_events.read() {
+        let (AssetEvent::Added { id }
+        | AssetEvent::Modified { id }
+        | AssetEvent::LoadedWithDependencies { id }
+        | AssetEvent::Removed { id }
+        | AssetEvent::Unused { id }) = event;
+        for &mesh in processed_meshes.morph_images.get(id).into_iter().flatten() {
+            removed.entry(mesh).or_insert(false);
         }
     }
 
-    for id in extract {
-        let mesh = mesh_assets.get(id).unwrap();
-        let mut cpu = CpuMesh {
-            aabb_min: Vec3::INFINITY,
-            aabb_max: Vec3::NEG_INFINITY,
-            indices: Vec::new(),
-            vertices: Vec::new(),
-        };
+    for (id, removed) in removed {
+        for meshes in processed_meshes.morph_images.values_mut() {
+            meshes.remove(&id);
+        }
 
-        let (
-            Some(VertexAttributeValues::Float32x3(positions)),
-            Some(VertexAttributeValues::Float32x3(normals)),
-            Some(VertexAttributeValues::Float32x2(uvs)),
-        ) = (
-            mesh.attribute(Mesh::ATTRIBUTE_POSITION),
-            mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
-            mesh.attribute(Mesh::ATTRIBUTE_UV_0),
-        )
-        else {
-            continue;
+        let cpu = match mesh_assets.get(id).filter(|_| !removed) {
+            Some(mesh) => match CpuMesh::from_mesh(mesh) {
+                Ok(mut cpu) => {
+                    if let Some(handle) = mesh.morph_targets() {
+                        processed_meshes
+                            .morph_images
+                            .entry(handle.id())
+                            .or_default()
+                            .insert(id);
+                        if let Some(image) = image_assets.get(handle) {
+                            if let Err(err) = cpu.set_morph_targets(image) {
+                                warn!("Morph targets of mesh {id} are ignored, {err}");
+                            }
+                        }
+                    }
+                    Some(cpu)
+                }
+                Err(err) => {
+                    warn!("Mesh {id} can't be path traced, {err}");
+                    None
+                }
+            },
+            None => None,
         };
 
-        for ((position, normal), uv) in positions.iter().zip(normals).zip(uvs) {
-            let position = Vec3::from_array(*position);
-            cpu.aabb_min = cpu.aabb_min.min(position);
-            cpu.aabb_max = cpu.aabb_max.max(position);
-
-            cpu.vertices.push(data::GpuVertex {
-                position,
-                normal: Vec3::from_array(*normal),
-                uv: Vec2::from_array(*uv),
-            });
+        // Modified meshes are replaced in their current slot
+        let existing = processed_meshes.asset_to_index.get(&id).copied();
+        match (existing, cpu) {
+            (Some(index), Some(cpu)) => processed_meshes.replace(index, cpu),
+            (Some(index), None) => {
+                processed_meshes.asset_to_index.remove(&id);
+                processed_meshes.remove(index);
+            }
+            (None, Some(cpu)) => {
+                let index = processed_meshes.insert(cpu);
+                processed_meshes.asset_to_index.insert(id, index);
+            }
+            // Meshes which failed to convert were never extracted
+            (None, None) => {}
         }
+    }
+    processed_meshes
+        .morph_images
+        .retain(|_, meshes| !meshes.is_empty());
+}
 
-        cpu.indices = match mesh.indices().unwrap() {
-            bevy::render::mesh::Indices::U16(vec) => {
-                vec.iter().cloned().map(|v| v as u32).collect()
-            }
-            bevy::render::mesh::Indices::U32(vec) => vec.clone(),
-        };
+/// Copies `data` into `range` of `buffer`, growing it when the range is past its end
+fn write_range<T: Copy + Default>(buffer: &mut Vec<T>, range: &Range<u32>, data: &[T]) {
+    let range = range.start as usize..range.end as usize;
+    if buffer.len() < range.end {
+        buffer.resize(range.end, T::default());
+    }
+    buffer[range].copy_from_slice(data);
+}
 
-        let index = processed_meshes.meshes.len();
-        processed_meshes.meshes.push(cpu);
-        processed_meshes.asset_to_index.insert(id, index);
+/// Uploads the `written` ranges of `buffer`, or all of it when it grew past the gpu buffer
+fn upload_ranges<T: ShaderType + ShaderSize + WriteInto>(
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+    buffer: &mut StorageBuffer<Vec<T>>,
+    uploaded_len: usize,
+    written: &[Range<u32>],
+) {
+    if buffer.buffer().is_none() || buffer.get().len() > uploaded_len {
+        buffer.write_buffer(render_device, render_queue);
+        return;
+    }
+
+    for range in written {
+        data::write_buffer_range(
+            buffer,
+            range.start as usize..range.end as usize,
+            render_queue,
+        );
     }
 }
 
@@ -105,43 +210,133 @@ pub fn prepare_meshes(
     mut processed_meshes: ResMut<ProcessedMeshes>,
     mut raytrace_meta: ResMut<RayTraceMeta>,
 ) {
-    if !processed_meshes.changed {
+    if processed_meshes.dirty.is_empty() && raytrace_meta.meshes.buffer().is_some() {
         return;
     }
-    processed_meshes.changed = false;
 
-    let mut meshes = Vec::with_capacity(processed_meshes.meshes.len());
-    let mut indices = Vec::new();
-    let mut vertices = Vec::new();
+    let processed = &mut *processed_meshes;
+    let meta = &mut *raytrace_meta;
+    let uploaded_lens = [
+        meta.meshes.get().len(),
+        meta.indices.get().len(),
+        meta.vertices.get().len(),
+        meta.nodes.get().len(),
+        meta.skins.get().len(),
+        meta.morphs.get().len(),
+    ];
+    let mut written: [Vec<Range<u32>>; 6] = Default::default();
+
+    let mut dirty = std::mem::take(&mut processed.dirty);
+    dirty.sort_unstable();
+    dirty.dedup();
+    for slot in dirty {
+        if processed.allocations.len() <= slot {
+            processed.allocations.resize_with(slot + 1, || None);
+        }
+        if let Some(old) = processed.allocations[slot].take() {
+            processed.vertex_allocator.free(old.vertices);
+            processed.index_allocator.free(old.indices);
+            processed.node_allocator.free(old.nodes);
+            processed.skin_allocator.free(old.skins);
+            processed.morph_allocator.free(old.morphs);
+        }
+
+        // Empty slots are never referenced by an object
+        let gpu_mesh = match &processed.meshes[slot] {
+            Some(mesh) => {
+                let allocation = MeshAllocation {
+                    vertices: processed.vertex_allocator.alloc(mesh.vertices.len() as u32),
+                    indices: processed.index_allocator.alloc(mesh.indices.len() as u32),
+                    nodes: processed.node_allocator.alloc(mesh.nodes.len() as u32),
+                    skins: processed.skin_allocator.alloc(mesh.skin.len() as u32),
+                    morphs: processed
+                        .morph_allocator
+                        .alloc(mesh.morph_targets.len() as u32),
+                };
 
-    for mesh in &processed_meshes.meshes {
-        let gpu_mesh = GpuMesh {
-            aabb_min: mesh.aabb_min,
-            aabb_max: mesh.aabb_max,
-            ihead: indices.len() as u32,
-            vhead: vertices.len() as u32,
-            tri_count: (mesh.indices.len() / 3) as u32,
+                write_range(meta.indices.get_mut(), &allocation.indices, &mesh.indices);
+                write_range(
+                    meta.vertices.get_mut(),
+                    &allocation.vertices,
+                    &mesh.vertices,
+                );
+                write_range(meta.nodes.get_mut(), &allocation.nodes, &mesh.nodes);
+                write_range(meta.skins.get_mut(), &allocation.skins, &mesh.skin);
+                write_range(
+                    meta.morphs.get_mut(),
+                    &allocation.morphs,
+                    &mesh.morph_targets,
+                );
+                written[1].push(allocation.indices.clone());
+                written[2].push(allocation.vertices.clone());
+                written[3].push(allocation.nodes.clone());
+                written[4].push(allocation.skins.clone());
+                written[5].push(allocation.morphs.clone());
+
+                let gpu_mesh = GpuMesh {
+                    aabb_min: mesh.aabb_min,
+                    aabb_max: mesh.aabb_max,
+                    ihead: allocation.indices.start,
+                    vhead: allocation.vertices.start,
+                    nhead: allocation.nodes.start,
+                    tri_count: (mesh.indices.len() / 3) as u32,
+                };
+                processed.allocations[slot] = Some(allocation);
+                gpu_mesh
+            }
+            None => GpuMesh::default(),
         };
 
-        indices.extend_from_slice(&mesh.indices);
-        vertices.extend_from_slice(&mesh.vertices);
-        meshes.push(gpu_mesh);
+        let slot = slot as u32..slot as u32 + 1;
+        write_range(meta.meshes.get_mut(), &slot, &[gpu_mesh]);
+        written[0].push(slot);
     }
 
     // Write
-    *(raytrace_meta.meshes.get_mut()) = meshes;
-    *(raytrace_meta.indices.get_mut()) = indices;
-    *(raytrace_meta.vertices.get_mut()) = vertices;
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.meshes,
+        uploaded_lens[0],
+        &written[0],
+    );
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.indices,
+        uploaded_lens[1],
+        &written[1],
+    );
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.vertices,
+        uploaded_lens[2],
+        &written[2],
+    );
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.nodes,
+        uploaded_lens[3],
+        &written[3],
+    );
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.skins,
+        uploaded_lens[4],
+        &written[4],
+    );
+    upload_ranges(
+        &render_device,
+        &render_queue,
+        &mut meta.morphs,
+        uploaded_lens[5],
+        &written[5],
+    );
 
-    raytrace_meta
-        .meshes
-        .write_buffer(&render_device, &render_queue);
-    raytrace_meta
-        .indices
-        .write_buffer(&render_device, &render_queue);
-    raytrace_meta
-        .vertices
-        .write_buffer(&render_device, &render_queue);
+    meta.generation += 1;
 
     debug!("Wrote meshes to gpu buffer");
 }
@@ -189,130 +384,431 @@ pub fn prepare_meshes(
 // }
 
 pub fn extract_materials(
-    render_device: Extract<Res<RenderDevice>>,
-    render_queue: Extract<Res<RenderQueue>>,
     material_assets: Extract<Res<Assets<StandardMaterial>>>,
+    mut asset_events: Extract<EventReader<AssetEvent<StandardMaterial>>>,
     mut raytrace_meta: ResMut<RayTraceMeta>,
-    mut material_count: Local<usize>,
 ) {
-    if material_assets.len() == *material_count {
-        return;
+    let mut remove = Vec::new();
+    let mut extract = Vec::new();
+
+    for event in asset_events.read() {
+        match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => {
+                extract.push(*id);
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                remove.push(*id);
+            }
+        }
     }
 
-    raytrace_meta.handle_to_material.clear();
-    *material_count = material_assets.len();
+    let meta = &mut *raytrace_meta;
+    let materials = meta.materials.get_mut();
 
-    let mut materials = Vec::new();
+    for &id in remove.iter().chain(&extract) {
+        for materials in meta.texture_to_materials.values_mut() {
+            materials.remove(&id);
+        }
+    }
+    meta.texture_to_materials
+        .retain(|_, materials| !materials.is_empty());
+
+    for id in remove {
+        let Some(index) = meta.handle_to_material.remove(&id.untyped()) else {
+            continue;
+        };
 
-    for (id, material) in material_assets.iter() {
-        raytrace_meta
+        // The last material takes the removed one's slot
+        materials.swap_remove(index);
+        if let Some(moved) = meta
             .handle_to_material
-            .insert(id.untyped(), materials.len());
+            .values_mut()
+            .find(|i| **i == materials.len())
+        {
+            *moved = index;
+        }
+        meta.materials_resized = true;
+    }
 
-        let albedo_texture = material
-            .base_color_texture
-            .as_ref()
-            .map(|handle| raytrace_meta.handle_to_texture.get(&handle.id().untyped()))
-            .flatten();
-        let emissive_texture = material
-            .emissive_texture
-            .as_ref()
-            .map(|handle| raytrace_meta.handle_to_texture.get(&handle.id().untyped()))
-            .flatten();
-        let metallic_roughness_texture = material
-            .metallic_roughness_texture
-            .as_ref()
-            .map(|handle| raytrace_meta.handle_to_texture.get(&handle.id().untyped()))
-            .flatten();
-        let normal_map_texture = material
-            .normal_map_texture
-            .as_ref()
-            .map(|handle| raytrace_meta.handle_to_texture.get(&handle.id().untyped()))
-            .flatten();
-
-        materials.push(data::Material {
-            albedo: material.base_color.to_linear().to_vec3(),
-            albedo_texture: albedo_texture.map(|v| *v as u32).unwrap_or(u32::MAX),
-            emissive: material.emissive.to_vec3(),
-            emissive_texture: emissive_texture.map(|v| *v as u32).unwrap_or(u32::MAX),
-            roughness: material.perceptual_roughness,
-            metallic: material.metallic,
-            metallic_roughness_texture: metallic_roughness_texture
-                .map(|v| *v as u32)
-                .unwrap_or(u32::MAX),
-            reflectance: material.reflectance,
-            normal_map_texture: normal_map_texture.map(|v| *v as u32).unwrap_or(u32::MAX),
-        });
+    for id in extract {
+        let Some(material) = material_assets.get(id) else {
+            continue;
+        };
+        for handle in material_textures(material) {
+            meta.texture_to_materials
+                .entry(handle.id().untyped())
+                .or_default()
+                .insert(id);
+        }
+        let material = data::Material::from_standard_material(material, &meta.handle_to_texture);
+
+        match meta.handle_to_material.get(&id.untyped()) {
+            Some(&index) => {
+                materials[index] = material;
+                meta.modified_materials.push(index);
+            }
+            None => {
+                meta.handle_to_material
+                    .insert(id.untyped(), materials.len());
+                materials.push(material);
+                meta.materials_resized = true;
+            }
+        }
     }
 
-    // Material Meta
-    *(raytrace_meta.materials.get_mut()) = materials;
+    // Materials using a texture which loaded, moved or was dropped are resolved again
+    let dependents: HashSet<_> = meta
+        .changed_textures
+        .drain()
+        .filter_map(|texture| meta.texture_to_materials.get(&texture))
+        .flatten()
+        .copied()
+        .collect();
+    for id in dependents {
+        let (Some(material), Some(&index)) = (
+            material_assets.get(id),
+            meta.handle_to_material.get(&id.untyped()),
+        ) else {
+            continue;
+        };
+        materials[index] =
+            data::Material::from_standard_material(material, &meta.handle_to_texture);
+        meta.modified_materials.push(index);
+    }
+}
 
-    raytrace_meta
-        .materials
-        .write_buffer(&render_device, &render_queue);
+pub fn prepare_materials(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut raytrace_meta: ResMut<RayTraceMeta>,
+) {
+    let meta = &mut *raytrace_meta;
+    let mut modified = std::mem::take(&mut meta.modified_materials);
+
+    if std::mem::take(&mut meta.materials_resized) || meta.materials.buffer().is_none() {
+        meta.materials.write_buffer(&render_device, &render_queue);
+        debug!("Wrote materials to gpu buffer");
+    } else if modified.is_empty() {
+        return;
+    } else {
+        modified.sort_unstable();
+        modified.dedup();
+        for index in modified {
+            data::write_buffer_range(&meta.materials, index..index + 1, &render_queue);
+        }
+    }
+
+    meta.generation += 1;
+}
 
-    debug!("Wrote materials to gpu buffer");
+/// Every texture `material` samples
+fn material_textures(material: &StandardMaterial) -> impl Iterator<Item = &Handle<Image>> {
+    [
+        &material.base_color_texture,
+        &material.emissive_texture,
+        &material.metallic_roughness_texture,
+        &material.normal_map_texture,
+    ]
+    .into_iter()
+    .flatten()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn extract_textures(
-    render_device: Extract<Res<RenderDevice>>,
-    render_queue: Extract<Res<RenderQueue>>,
+    render_device: Res<RenderDevice>,
     image_assets: Extract<Res<Assets<Image>>>,
+    mut asset_events: Extract<EventReader<AssetEvent<Image>>>,
+    material_assets: Extract<Res<Assets<StandardMaterial>>>,
+    query: Extract<Query<(&MeshMaterial3d<StandardMaterial>, &InheritedVisibility)>>,
+    texture_mode: Res<RayTraceTextureMode>,
     mut raytrace_meta: ResMut<RayTraceMeta>,
-    mut image_count: Local<usize>,
+    mut texture_refs: Local<HashSet<AssetId<Image>>>,
+    mut warned_formats: Local<HashSet<TextureFormat>>,
 ) {
-    if image_assets.len() == *image_count {
-        return;
+    // Every loaded image sampled by the materials of visible objects,
+    // images which leave this set are dropped from the buffer
+    let mut refs = HashSet::new();
+    for (mat_handle, visibility) in query.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let Some(material) = material_assets.get(mat_handle) else {
+            continue;
+        };
+
+        for handle in material_textures(material) {
+            if image_assets.contains(handle) {
+                refs.insert(handle.id());
+            }
+        }
     }
 
-    raytrace_meta.handle_to_texture.clear();
-    *image_count = image_assets.len();
+    let unchanged = refs == *texture_refs;
+    *texture_refs = refs;
 
-    let mut textures = Vec::new();
-    let mut texture_data = TextureData::default();
+    // Added and removed images change the references above, modified ones are replaced in place
+    let modified: Vec<_> = asset_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } if texture_refs.contains(id) => Some(*id),
+            _ => None,
+        })
+        .collect();
 
-    for (id, image) in image_assets.iter() {
-        raytrace_meta
-            .handle_to_texture
-            .insert(id.untyped(), textures.len());
+    if unchanged && raytrace_meta.textures.buffer().is_some() {
+        let mut replaced = true;
+        for &id in &modified {
+            let Some(image) = image_assets.get(id) else {
+                continue;
+            };
+            replaced &=
+                replace_texture(&render_device, *texture_mode, &mut raytrace_meta, id, image);
+            if !replaced {
+                break;
+            }
+        }
 
-        textures.push(texture_data.append_texture(image));
+        if replaced {
+            return;
+        }
     }
 
+    let old_handle_to_texture = std::mem::take(&mut raytrace_meta.handle_to_texture);
+    let old_images = std::mem::take(&mut raytrace_meta.texture_images);
+
+    // Images keep their order while they stay referenced so fewer materials change,
+    // new ones follow sorted to not depend on the set's iteration order
+    let mut new_images: Vec<_> = texture_refs
+        .iter()
+        .filter(|id| !old_handle_to_texture.contains_key(&id.untyped()))
+        .copied()
+        .collect();
+    new_images.sort_unstable();
+    let images = old_images
+        .iter()
+        .filter(|id| texture_refs.contains(*id))
+        .copied()
+        .chain(new_images);
+
+    let max_textures = shader::texture_binding_array_size(&render_device) as usize;
+    let (textures, texture_data) = collect_textures(
+        *texture_mode,
+        max_textures,
+        images.filter_map(|id| Some((id, image_assets.get(id)?))),
+        |image| shader::can_bind_in_array(&render_device, image),
+        &mut warned_formats,
+    );
+    let textures: Vec<Texture> = textures
+        .into_iter()
+        .enumerate()
+        .map(|(index, (id, texture))| {
+            raytrace_meta.handle_to_texture.insert(id.untyped(), index);
+            raytrace_meta.texture_images.push(id);
+            texture
+        })
+        .collect();
+
+    let meta = &mut *raytrace_meta;
+    meta.changed_textures.extend(
+        old_handle_to_texture
+            .iter()
+            .filter(|(id, index)| meta.handle_to_texture.get(*id) != Some(index))
+            .chain(
+                meta.handle_to_texture
+                    .iter()
+                    .filter(|(id, _)| !old_handle_to_texture.contains_key(*id)),
+            )
+            .map(|(id, _)| *id),
+    );
+
     // Texture Meta
+    raytrace_meta.texture_bytes = texture_data.size_in_bytes();
     *(raytrace_meta.textures.get_mut()) = textures;
     *(raytrace_meta.texture_data.get_mut()) = texture_data.data;
+    raytrace_meta.textures_resized = true;
+}
 
-    raytrace_meta
-        .textures
-        .write_buffer(&render_device, &render_queue);
-    raytrace_meta
-        .texture_data
-        .write_buffer(&render_device, &render_queue);
+pub fn prepare_textures(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut raytrace_meta: ResMut<RayTraceMeta>,
+) {
+    let meta = &mut *raytrace_meta;
+    let mut modified = std::mem::take(&mut meta.modified_textures);
+    let modified_data = std::mem::take(&mut meta.modified_texture_data);
+
+    if std::mem::take(&mut meta.textures_resized) || meta.textures.buffer().is_none() {
+        meta.textures.write_buffer(&render_device, &render_queue);
+        meta.texture_data
+            .write_buffer(&render_device, &render_queue);
+        debug!(
+            "Wrote {} textures to gpu buffer, {:.2} MiB",
+            meta.textures.get().len(),
+            meta.texture_bytes as f32 / (1024.0 * 1024.0)
+        );
+    } else if modified.is_empty() {
+        return;
+    } else {
+        modified.sort_unstable();
+        modified.dedup();
+        for index in modified {
+            data::write_buffer_range(&meta.textures, index..index + 1, &render_queue);
+        }
+        for range in modified_data {
+            data::write_buffer_range(&meta.texture_data, range, &render_queue);
+        }
+    }
+
+    meta.generation += 1;
+}
+
+/// Textures of `images` in the order they are bound, and their data when it's kept in a storage buffer.
+/// Images which can't be traced are skipped so their materials fall back to their constant factors
+fn collect_textures<'a>(
+    texture_mode: RayTraceTextureMode,
+    max_textures: usize,
+    images: impl IntoIterator<Item = (AssetId<Image>, &'a Image)>,
+    can_bind: impl Fn(&Image) -> bool,
+    warned_formats: &mut HashSet<TextureFormat>,
+) -> (Vec<(AssetId<Image>, Texture)>, TextureData) {
+    let mut textures = Vec::new();
+    let mut texture_data = TextureData::default();
+
+    for (id, image) in images {
+        let texture = match texture_mode {
+            RayTraceTextureMode::BindingArray => {
+                if textures.len() == max_textures {
+                    warn_once!(
+                        "Only {max_textures} textures fit in the binding array, image {id} and any \
+                        after it will be skipped"
+                    );
+                    continue;
+                }
+                can_bind(image).then(|| Texture::from_image(image))
+            }
+            RayTraceTextureMode::StorageBuffer => texture_data.append_texture(image),
+        };
+
+        // Images with too little data are reported by `append_texture`
+        let Some(texture) = texture else {
+            let format = image.texture_descriptor.format;
+            let unsupported = texture_mode == RayTraceTextureMode::BindingArray
+                || Texture::from_image(image).format == 0;
+            if unsupported && warned_formats.insert(format) {
+                warn!("Textures with format {format:?} can't be path traced and will be skipped");
+            }
+            continue;
+        };
+
+        textures.push((id, texture));
+    }
 
-    debug!("Wrote textures to gpu buffer");
+    (textures, texture_data)
 }
 
+/// Rewrites the texture of a modified image in its current slot, returns false when
+/// it doesn't fit there anymore and all textures have to be rebuilt
+fn replace_texture(
+    render_device: &RenderDevice,
+    texture_mode: RayTraceTextureMode,
+    raytrace_meta: &mut RayTraceMeta,
+    id: AssetId<Image>,
+    image: &Image,
+) -> bool {
+    let Some(&index) = raytrace_meta.handle_to_texture.get(&id.untyped()) else {
+        return false;
+    };
+
+    let texture = match texture_mode {
+        RayTraceTextureMode::BindingArray => {
+            if !shader::can_bind_in_array(render_device, image) {
+                return false;
+            }
+            Texture::from_image(image)
+        }
+        RayTraceTextureMode::StorageBuffer => {
+            let old = raytrace_meta.textures.get()[index];
+            let mut texture_data = TextureData::default();
+            let Some(mut texture) = texture_data.append_texture(image) else {
+                return false;
+            };
+            if (texture.width, texture.height, texture.format)
+                != (old.width, old.height, old.format)
+            {
+                return false;
+            }
+
+            texture.offset = old.offset;
+            let range = old.offset as usize..old.offset as usize + texture_data.data.len();
+            raytrace_meta.texture_data.get_mut()[range.clone()].copy_from_slice(&texture_data.data);
+            raytrace_meta.modified_texture_data.push(range);
+            texture
+        }
+    };
+
+    raytrace_meta.textures.get_mut()[index] = texture;
+    raytrace_meta.modified_textures.push(index);
+    true
+}
+
+/// Rebuild the top level hierarchy instead of refitting it when more than
+/// `1 / TLAS_REBUILD_RATIO` of the objects moved
+const TLAS_REBUILD_RATIO: usize = 4;
+
+#[derive(Resource, Default)]
+pub struct TopLevelBvh {
+    pub entities: Vec<Entity>,
+    pub transforms: Vec<Mat4>,
+    pub nodes: Vec<BvhNode>,
+    pub primitives: Vec<u32>,
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub fn extract_visible(
     render_device: Extract<Res<RenderDevice>>,
     render_queue: Extract<Res<RenderQueue>>,
 
     material_assets: Extract<Res<Assets<StandardMaterial>>>,
-    query: Extract<Query<(&GlobalTransform, &Mesh3d, &MeshMaterial3d<StandardMaterial>)>>,
+    query: Extract<
+        Query<
+            (
+                Entity,
+                &GlobalTransform,
+                &Mesh3d,
+                &MeshMaterial3d<StandardMaterial>,
+                &InheritedVisibility,
+            ),
+            Without<RayTracePrimitive>,
+        >,
+    >,
+    primitive_query: Extract<
+        Query<(
+            Entity,
+            &GlobalTransform,
+            &RayTracePrimitive,
+            &MeshMaterial3d<StandardMaterial>,
+            &InheritedVisibility,
+        )>,
+    >,
     processed_meshes: Res<ProcessedMeshes>,
+    skinned_meshes: Res<SkinnedMeshes>,
+    mut tlas: ResMut<TopLevelBvh>,
     mut raytrace_meta: ResMut<RayTraceMeta>,
 ) {
     let mut objects = Vec::new();
-    let mut emissives = Vec::new();
+    let mut entities = Vec::new();
+    let mut transforms = Vec::new();
+    let mut bounds = Vec::new();
+    let mut luminance = Vec::new();
+    let mut primitives = Vec::new();
+    let mut shapes = Vec::new();
 
-    for (transform, mesh_handle, mat_handle) in query.iter() {
-        if let Some(mat) = material_assets.get(mat_handle) {
-            if mat.emissive.red > 0.0 || mat.emissive.green > 0.0 || mat.emissive.blue > 0.0 {
-                emissives.push(objects.len() as u32);
-            }
+    for (entity, transform, mesh_handle, mat_handle, visibility) in query.iter() {
+        if !visibility.get() {
+            continue;
         }
-
         let Some(&mesh) = processed_meshes.asset_to_index.get(&mesh_handle.id()) else {
             continue;
         };
@@ -323,24 +819,637 @@ pub fn extract_visible(
             continue;
         };
 
-        let local_to_world = transform.compute_matrix();
+        let Some(cpu_mesh) = &processed_meshes.meshes[mesh] else {
+            continue;
+        };
+
+        luminance.push(
+            material_assets
+                .get(mat_handle)
+                .map_or(0.0, |mat| mat.emissive.luminance()),
+        );
+
+        // Skinned vertices are already in world space, morphed ones are still in mesh space
+        let (mesh, local_to_world, mesh_bounds) = match skinned_meshes.instances.get(&entity) {
+            Some(instance) if !instance.joints.is_empty() => {
+                (instance.slot, Mat4::IDENTITY, instance.bounds)
+            }
+            instance => {
+                let local_to_world = transform.compute_matrix();
+                let (mesh, mesh_bounds) = match instance {
+                    Some(instance) => (instance.slot, instance.bounds),
+                    None => (
+                        mesh,
+                        Bounds {
+                            min: cpu_mesh.aabb_min,
+                            max: cpu_mesh.aabb_max,
+                        },
+                    ),
+                };
+                (
+                    mesh,
+                    local_to_world,
+                    mesh_bounds.transformed(&local_to_world),
+                )
+            }
+        };
+        bounds.push(mesh_bounds);
+        entities.push(entity);
+        transforms.push(local_to_world);
+
         objects.push(data::Object {
             world_to_local: local_to_world.inverse(),
             local_to_world,
 
             mat: mat as u32,
             mesh: mesh as u32,
+            primitive: u32::MAX,
+            emissive: u32::MAX,
+        });
+    }
+
+    for (entity, transform, primitive, mat_handle, visibility) in primitive_query.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let Some(&mat) = raytrace_meta
+            .handle_to_material
+            .get(&mat_handle.id().untyped())
+        else {
+            continue;
+        };
+
+        luminance.push(
+            material_assets
+                .get(mat_handle)
+                .map_or(0.0, |mat| mat.emissive.luminance()),
+        );
+
+        let local_to_world = transform.compute_matrix();
+        bounds.push(primitive.bounds().transformed(&local_to_world));
+        entities.push(entity);
+        transforms.push(local_to_world);
+
+        objects.push(data::Object {
+            world_to_local: local_to_world.inverse(),
+            local_to_world,
+
+            mat: mat as u32,
+            mesh: u32::MAX,
+            primitive: primitives.len() as u32,
+            emissive: u32::MAX,
         });
+        primitives.push(data::Primitive::from(*primitive));
+        shapes.push(*primitive);
+    }
+
+    // Top Level BVH
+    let moved = if tlas.entities == entities {
+        tlas.transforms
+            .iter()
+            .zip(&transforms)
+            .filter(|(a, b)| a != b)
+            .count()
+    } else {
+        usize::MAX
+    };
+
+    if moved == usize::MAX || moved * TLAS_REBUILD_RATIO > objects.len() {
+        let bvh = bvh::build(&bounds);
+        tlas.nodes = bvh.nodes;
+        tlas.primitives = bvh.primitives;
+    } else {
+        let tlas = &mut *tlas;
+        bvh::refit(&mut tlas.nodes, &tlas.primitives, &bounds);
+    }
+    tlas.entities = entities;
+    tlas.transforms = transforms;
+
+    // Store objects in leaf order so every leaf covers a contiguous range
+    let mut objects: Vec<data::Object> = tlas
+        .primitives
+        .iter()
+        .map(|&p| objects[p as usize])
+        .collect();
+
+    // Emissives are picked proportionally to their power and then uniformly by area,
+    // using the mesh triangles in their BVH order or the primitive's surface
+    let mut emissives = Vec::new();
+    let mut emissive_cdf = Vec::new();
+    let mut total_power = 0.0;
+    for (i, &p) in tlas.primitives.iter().enumerate() {
+        let luminance = luminance[p as usize];
+        if luminance <= 0.0 {
+            continue;
+        }
+
+        let object = &mut objects[i];
+        let cdf_head = emissive_cdf.len();
+        let mut area = 0.0;
+        if object.primitive != u32::MAX {
+            area = shapes[object.primitive as usize].area(&object.local_to_world);
+            if area <= 0.0 {
+                continue;
+            }
+        } else {
+            let Some(mesh) = &processed_meshes.meshes[object.mesh as usize] else {
+                continue;
+            };
+
+            // Deformed copies only keep the bind pose on the cpu, so their vertices are
+            // deformed here the same way the skinning pass does it this frame
+            let deformed = skinned_meshes
+                .instances
+                .get(&tlas.entities[p as usize])
+                .and_then(|instance| {
+                    let bind_pose = processed_meshes.meshes[instance.mesh].as_ref()?;
+                    Some(skinned_meshes.deformed_positions(instance, bind_pose))
+                });
+            let position = |v: u32| match &deformed {
+                Some(positions) => positions[v as usize],
+                None => mesh.vertices[v as usize].position,
+            };
+
+            for tri in mesh.indices.chunks_exact(3) {
+                let [a, b, c] = [tri[0], tri[1], tri[2]]
+                    .map(|v| object.local_to_world.transform_point3(position(v)));
+                area += (b - a).cross(c - a).length() * 0.5;
+                emissive_cdf.push(area);
+            }
+            if area <= 0.0 {
+                emissive_cdf.truncate(cdf_head);
+                continue;
+            }
+            emissive_cdf[cdf_head..].iter_mut().for_each(|c| *c /= area);
+        }
+
+        total_power += luminance * area;
+        object.emissive = emissives.len() as u32;
+        emissives.push(data::Emissive {
+            object: i as u32,
+            cdf_head: cdf_head as u32,
+            area,
+            power_cdf: total_power,
+        });
+    }
+    emissives
+        .iter_mut()
+        .for_each(|e| e.power_cdf /= total_power);
+
+    if raytrace_meta.objects.buffer().is_some()
+        && raytrace_meta.objects.get() == &objects
+        && raytrace_meta.primitives.get() == &primitives
+        && raytrace_meta.emissives.get() == &emissives
+        && raytrace_meta.emissive_cdf.get() == &emissive_cdf
+        && raytrace_meta.tlas.get() == &tlas.nodes
+    {
+        return;
     }
 
     // Query Meta
     *(raytrace_meta.objects.get_mut()) = objects;
+    *(raytrace_meta.primitives.get_mut()) = primitives;
     *(raytrace_meta.emissives.get_mut()) = emissives;
+    *(raytrace_meta.emissive_cdf.get_mut()) = emissive_cdf;
+    *(raytrace_meta.tlas.get_mut()) = tlas.nodes.clone();
 
     raytrace_meta
         .objects
         .write_buffer(&render_device, &render_queue);
+    raytrace_meta
+        .primitives
+        .write_buffer(&render_device, &render_queue);
     raytrace_meta
         .emissives
         .write_buffer(&render_device, &render_queue);
+    raytrace_meta
+        .emissive_cdf
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta
+        .tlas
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta.generation += 1;
+}
+
+pub fn extract_lights(
+    render_device: Extract<Res<RenderDevice>>,
+    render_queue: Extract<Res<RenderQueue>>,
+
+    // Lights outside the camera frustum still light what it sees, so only hidden ones are skipped
+    point_lights: Extract<Query<(&PointLight, &GlobalTransform, &InheritedVisibility)>>,
+    spot_lights: Extract<Query<(&SpotLight, &GlobalTransform, &InheritedVisibility)>>,
+    directional_lights: Extract<Query<(&DirectionalLight, &GlobalTransform, &InheritedVisibility)>>,
+    mut raytrace_meta: ResMut<RayTraceMeta>,
+) {
+    let mut lights = Vec::new();
+
+    // Same units as Bevy's rasterizer, lumens are converted to candela
+    for (light, transform, visibility) in point_lights.iter() {
+        if !visibility.get() {
+            continue;
+        }
+
+        lights.push(data::Light {
+            position: transform.translation(),
+            kind: data::Light::POINT,
+            inverse_range_squared: 1.0 / (light.range * light.range),
+            color: light.color.to_linear().to_vec3() * light.intensity / (4.0 * PI),
+            radius: light.radius,
+            ..default()
+        });
+    }
+
+    for (light, transform, visibility) in spot_lights.iter() {
+        if !visibility.get() {
+            continue;
+        }
+
+        let cos_outer = light.outer_angle.cos();
+        let spot_scale = 1.0 / (light.inner_angle.cos() - cos_outer).max(1e-4);
+        lights.push(data::Light {
+            position: transform.translation(),
+            kind: data::Light::SPOT,
+            direction: transform.forward().as_vec3(),
+            inverse_range_squared: 1.0 / (light.range * light.range),
+            color: light.color.to_linear().to_vec3() * light.intensity / (4.0 * PI),
+            radius: light.radius,
+            spot_scale,
+            spot_offset: -cos_outer * spot_scale,
+        });
+    }
+
+    for (light, transform, visibility) in directional_lights.iter() {
+        if !visibility.get() {
+            continue;
+        }
+
+        lights.push(data::Light {
+            kind: data::Light::DIRECTIONAL,
+            direction: transform.back().as_vec3(),
+            color: light.color.to_linear().to_vec3() * light.illuminance,
+            ..default()
+        });
+    }
+
+    if raytrace_meta.lights.buffer().is_some() && raytrace_meta.lights.get() == &lights {
+        return;
+    }
+
+    // Light Meta
+    *(raytrace_meta.lights.get_mut()) = lights;
+
+    raytrace_meta
+        .lights
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta.generation += 1;
+
+    debug!("Wrote lights to gpu buffer");
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::RenderAssetUsages,
+        render::{
+            mesh::{
+                morph::{MorphAttributes, MorphTargetImage},
+                skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+                VertexAttributeValues,
+            },
+            render_resource::{Extent3d, TextureDimension},
+            MainWorld, RenderApp,
+        },
+    };
+
+    use super::*;
+    use crate::skinning::SkinnedInstance;
+
+    /// App whose render sub app only runs the extract schedule, there's no gpu for the rest
+    fn headless_app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Mesh>()
+            .init_asset::<Image>()
+            .init_asset::<StandardMaterial>();
+
+        let mut render_app = SubApp::new();
+        render_app.set_extract(|main_world, render_world| {
+            let mut extracted = MainWorld::default();
+            *extracted = std::mem::take(main_world);
+            render_world.insert_resource(extracted);
+            render_world.run_schedule(ExtractSchedule);
+            let mut extracted = render_world.remove_resource::<MainWorld>().unwrap();
+            *main_world = std::mem::take(&mut *extracted);
+        });
+        render_app
+            .init_resource::<RayTraceMeta>()
+            .init_resource::<ProcessedMeshes>();
+        app.insert_sub_app(RenderApp, render_app);
+        app
+    }
+
+    #[test]
+    fn modified_meshes_keep_their_slot() {
+        let mut app = headless_app();
+        app.sub_app_mut(RenderApp)
+            .add_systems(ExtractSchedule, extract_meshes);
+
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<Mesh>>()
+            .add(Cuboid::default());
+        for _ in 0..100 {
+            let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+            meshes.get_mut(&handle).unwrap().translate_by(Vec3::X);
+            app.update();
+        }
+
+        let processed = app.sub_app(RenderApp).world().resource::<ProcessedMeshes>();
+        assert_eq!(processed.asset_to_index.len(), 1);
+        assert_eq!(processed.meshes.iter().flatten().count(), 1);
+        let mesh = processed.meshes[processed.asset_to_index[&handle.id()]]
+            .as_ref()
+            .unwrap();
+        assert_eq!(mesh.aabb_min.x, 99.5);
+    }
+
+    #[test]
+    fn meshes_are_converted_again_when_their_morph_targets_load() {
+        let mut app = headless_app();
+        app.sub_app_mut(RenderApp)
+            .add_systems(ExtractSchedule, extract_meshes);
+
+        let world = app.world_mut();
+        let image = world.resource::<Assets<Image>>().reserve_handle();
+        let mut mesh = Mesh::from(Cuboid::default());
+        let vertex_count = mesh.count_vertices();
+        mesh.set_morph_targets(image.clone());
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        app.update();
+
+        let morph_targets = |app: &App| {
+            let processed = app.sub_app(RenderApp).world().resource::<ProcessedMeshes>();
+            let index = processed.asset_to_index[&mesh.id()];
+            processed.meshes[index]
+                .as_ref()
+                .unwrap()
+                .morph_targets
+                .len()
+        };
+        assert_eq!(morph_targets(&app), 0);
+
+        let targets = (0..2).map(|_| (0..vertex_count).map(|_| MorphAttributes::default()));
+        let morph_image =
+            MorphTargetImage::new(targets, vertex_count, RenderAssetUsages::default()).unwrap();
+        app.world_mut()
+            .resource_mut::<Assets<Image>>()
+            .insert(&image, morph_image.0);
+        app.update();
+        assert_eq!(morph_targets(&app), vertex_count * 2);
+
+        app.world_mut()
+            .resource_mut::<Assets<Image>>()
+            .remove(&image);
+        app.update();
+        assert_eq!(morph_targets(&app), 0);
+
+        let processed = app.sub_app(RenderApp).world().resource::<ProcessedMeshes>();
+        assert_eq!(processed.meshes.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn skinned_meshes_get_a_copy_within_their_joints() {
+        let mut app = headless_app();
+        app.init_asset::<SkinnedMeshInverseBindposes>();
+        app.sub_app_mut(RenderApp)
+            .init_resource::<SkinnedMeshes>()
+            .add_systems(
+                ExtractSchedule,
+                (extract_meshes, crate::skinning::extract_skins).chain(),
+            );
+
+        let mut mesh = Mesh::from(Cuboid::default());
+        let count = mesh.count_vertices();
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_INDEX,
+            VertexAttributeValues::Uint16x4(vec![[0; 4]; count]),
+        );
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_JOINT_WEIGHT,
+            vec![[1.0, 0.0, 0.0, 0.0]; count],
+        );
+
+        let world = app.world_mut();
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let inverse_bindposes = world
+            .resource_mut::<Assets<SkinnedMeshInverseBindposes>>()
+            .add(SkinnedMeshInverseBindposes::from(vec![Mat4::IDENTITY]));
+        let joint = world
+            .spawn(GlobalTransform::from_translation(Vec3::X * 5.0))
+            .id();
+        let entity = world
+            .spawn((
+                Mesh3d(mesh.clone()),
+                SkinnedMesh {
+                    inverse_bindposes,
+                    joints: vec![joint],
+                },
+                InheritedVisibility::VISIBLE,
+            ))
+            .id();
+        app.update();
+
+        let render_world = app.sub_app(RenderApp).world();
+        let skinned = render_world.resource::<SkinnedMeshes>();
+        let processed = render_world.resource::<ProcessedMeshes>();
+        let instance = &skinned.instances[&entity];
+        assert_eq!(
+            instance.bounds,
+            Bounds {
+                min: Vec3::new(4.5, -0.5, -0.5),
+                max: Vec3::new(5.5, 0.5, 0.5),
+            }
+        );
+        assert_eq!(skinned.joints, [Mat4::from_translation(Vec3::X * 5.0)]);
+
+        let source = processed.meshes[instance.mesh].as_ref().unwrap();
+        let copy = processed.meshes[instance.slot].as_ref().unwrap();
+        assert_ne!(instance.mesh, instance.slot);
+        assert!(!source.skin.is_empty() && copy.skin.is_empty());
+        assert_eq!(skinned.max_depth, copy.depth);
+
+        // Emissive areas are measured on the positions the skinning pass writes
+        let positions = skinned.deformed_positions(instance, source);
+        assert!(positions
+            .iter()
+            .zip(&source.vertices)
+            .all(|(&p, v)| p == v.position + Vec3::X * 5.0));
+
+        // Despawned objects give their copy back
+        app.world_mut().despawn(entity);
+        app.update();
+        let render_world = app.sub_app(RenderApp).world();
+        assert!(render_world
+            .resource::<SkinnedMeshes>()
+            .instances
+            .is_empty());
+        let processed = render_world.resource::<ProcessedMeshes>();
+        assert_eq!(processed.meshes.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn deformed_positions_blend_morph_targets_before_skinning() {
+        let mut mesh = CpuMesh::from_mesh(&Mesh::from(Cuboid::default())).unwrap();
+        let count = mesh.vertices.len();
+        mesh.skin = vec![
+            data::SkinVertex {
+                joints: UVec4::ZERO,
+                weights: Vec4::X,
+            };
+            count
+        ];
+        mesh.morph_targets = [Vec3::X, Vec3::Y]
+            .into_iter()
+            .flat_map(|position| {
+                let delta = data::MorphDelta {
+                    position,
+                    ..default()
+                };
+                vec![delta; count]
+            })
+            .collect();
+
+        let skinned = SkinnedMeshes {
+            joints: vec![Mat4::from_scale(Vec3::splat(2.0))],
+            weights: vec![0.5, 0.0],
+            ..default()
+        };
+        let instance = SkinnedInstance {
+            mesh: 0,
+            slot: 1,
+            joints: 0..1,
+            weights: 0..2,
+            bounds: Bounds::EMPTY,
+        };
+        let positions = skinned.deformed_positions(&instance, &mesh);
+        assert!(positions
+            .iter()
+            .zip(&mesh.vertices)
+            .all(|(&p, v)| p == (v.position + Vec3::X * 0.5) * 2.0));
+    }
+
+    #[test]
+    fn modified_materials_are_extracted() {
+        let mut app = headless_app();
+        app.sub_app_mut(RenderApp)
+            .add_systems(ExtractSchedule, extract_materials);
+
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(StandardMaterial::from_color(Color::WHITE));
+        app.update();
+
+        for color in [
+            Color::srgb(1.0, 0.0, 0.0),
+            Color::srgb(0.0, 1.0, 0.0),
+            Color::srgb(0.2, 0.4, 0.6),
+        ] {
+            let mut materials = app.world_mut().resource_mut::<Assets<StandardMaterial>>();
+            materials.get_mut(&handle).unwrap().base_color = color;
+            app.update();
+
+            let meta = app.sub_app(RenderApp).world().resource::<RayTraceMeta>();
+            let index = meta.handle_to_material[&handle.id().untyped()];
+            assert_eq!(meta.materials.get().len(), 1);
+            assert_eq!(
+                meta.materials.get()[index].albedo,
+                color.to_linear().to_vec3()
+            );
+            assert!(meta.modified_materials.contains(&index));
+        }
+    }
+
+    fn image(format: TextureFormat) -> Image {
+        if format.is_compressed() {
+            // A single block, `Image::new` can't size compressed data
+            let mut image = image(TextureFormat::R8Unorm);
+            image.texture_descriptor.format = format;
+            image.data = vec![255; format.block_copy_size(None).unwrap() as usize];
+            return image;
+        }
+
+        Image::new_fill(
+            Extent3d {
+                width: 4,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &vec![255; format.block_copy_size(None).unwrap() as usize],
+            format,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    fn images(formats: &[TextureFormat]) -> (Assets<Image>, Vec<AssetId<Image>>) {
+        let mut assets = Assets::default();
+        let ids = formats
+            .iter()
+            .map(|&format| assets.add(image(format)).id())
+            .collect();
+        (assets, ids)
+    }
+
+    #[test]
+    fn storage_buffer_mode_packs_texture_data() {
+        let (images, ids) = images(&[
+            TextureFormat::Rgba8UnormSrgb,
+            TextureFormat::Bc1RgbaUnorm,
+            TextureFormat::R8Unorm,
+        ]);
+        let (textures, texture_data) = collect_textures(
+            RayTraceTextureMode::StorageBuffer,
+            1,
+            ids.iter().map(|&id| (id, images.get(id).unwrap())),
+            |_| panic!("storage buffers don't bind images"),
+            &mut HashSet::new(),
+        );
+
+        // Compressed images are skipped unless they're decoded on the CPU
+        let expected = if cfg!(feature = "compressed_textures") {
+            3
+        } else {
+            2
+        };
+        assert_eq!(textures.len(), expected);
+        assert_eq!(textures[0].1.offset, 0);
+        let last = textures.last().unwrap().1;
+        assert_eq!(
+            texture_data.data.len() as u32,
+            last.level_offset(last.mip_levels)
+        );
+    }
+
+    #[test]
+    fn binding_array_mode_binds_images() {
+        let (images, ids) = images(&[
+            TextureFormat::Rgba8UnormSrgb,
+            TextureFormat::Rgba32Float,
+            TextureFormat::Rgba8Unorm,
+            TextureFormat::R8Unorm,
+        ]);
+        let (textures, texture_data) = collect_textures(
+            RayTraceTextureMode::BindingArray,
+            2,
+            ids.iter().map(|&id| (id, images.get(id).unwrap())),
+            |image| image.texture_descriptor.format != TextureFormat::Rgba32Float,
+            &mut HashSet::new(),
+        );
+
+        // Unbindable images are skipped and the rest stop at the limit
+        assert!(texture_data.data.is_empty());
+        let bound: Vec<_> = textures.iter().map(|(id, _)| *id).collect();
+        assert_eq!(bound, [ids[0], ids[2]]);
+    }
 }
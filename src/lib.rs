@@ -1,7 +1,16 @@
 #![feature(f16)]
+mod accumulation;
+mod alloc;
+pub mod bsdf;
+pub mod bvh;
+#[cfg(feature = "compressed_textures")]
+mod compressed;
 pub mod data;
 mod extract;
 pub mod shader;
+mod skinning;
+#[cfg(test)]
+mod test_utils;
 
-pub use data::RayTraceSettings;
+pub use data::{RayTraceAccumulation, RayTracePrimitive, RayTraceSettings, RayTraceTextureMode};
 pub use shader::RayTracePlugin;
@@ -0,0 +1,62 @@
+#define_import_path path_tracing::lights
+
+#import bevy_render::maths::PI
+
+#import path_tracing::math::INFINITY
+
+// Bindings
+@group(1) @binding(3) var<storage> lights: array<Light>;
+
+// Light Types, must match `data::Light`
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_SPOT: u32 = 1u;
+const LIGHT_DIRECTIONAL: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    inverse_range_squared: f32,
+    color: vec3<f32>,
+    radius: f32,
+    spot_scale: f32,
+    spot_offset: f32,
+}
+
+struct LightSample {
+    // Direction from the surface towards the light
+    dir: vec3<f32>,
+    distance: f32,
+    // Light arriving along `dir`, before the cosine term
+    radiance: vec3<f32>,
+}
+
+// Samples a light as seen from `p`, `rng` picks a point on the light's sphere for soft shadows
+fn sample_light(light: Light, p: vec3<f32>, rng: vec3<f32>) -> LightSample {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return LightSample(light.direction, INFINITY, light.color);
+    }
+
+    let z = 1.0 - 2.0 * rng.x;
+    let r = sqrt(max(1.0 - z * z, 0.0));
+    let phi = 2.0 * PI * rng.y;
+    let position = light.position + vec3<f32>(r * cos(phi), r * sin(phi), z) * light.radius;
+
+    let to_light = position - p;
+    let distance_squared = max(dot(to_light, to_light), 0.0001);
+    let distance = sqrt(distance_squared);
+    let dir = to_light / distance;
+
+    // Inverse square falloff windowed by the range, same as Bevy's `getDistanceAttenuation`
+    let factor = distance_squared * light.inverse_range_squared;
+    let window = saturate(1.0 - factor * factor);
+    var radiance = light.color * window * window / distance_squared;
+
+    if light.kind == LIGHT_SPOT {
+        let cd = dot(-dir, light.direction);
+        let attenuation = saturate(cd * light.spot_scale + light.spot_offset);
+        radiance *= attenuation * attenuation;
+    }
+
+    return LightSample(dir, distance, radiance);
+}
@@ -1,16 +1,29 @@
 #define_import_path path_tracing::query
 
-#import bevy_render::maths::{PI, HALF_PI}
+#import bevy_render::maths::{PI, HALF_PI, PI_2}
 
 #import path_tracing::math::{EPSILON, U32_MAX, INFINITY, T_MIN}
 
 // Bindings
 @group(1) @binding(0) var<storage> objects: array<Object>;
-@group(1) @binding(1) var<storage> emissives: array<u32>;
+@group(1) @binding(1) var<storage> emissives: array<Emissive>;
+@group(1) @binding(2) var<storage> tlas: array<BvhNode>;
+@group(1) @binding(4) var<storage> emissive_cdf: array<f32>;
+@group(1) @binding(5) var<storage> primitives: array<Primitive>;
 
 @group(2) @binding(0) var<storage> meshes: array<Mesh>;
 @group(2) @binding(1) var<storage> indices: array<u32>;
 @group(2) @binding(2) var<storage> vertices: array<Vertex>;
+@group(2) @binding(3) var<storage> nodes: array<BvhNode>;
+
+// Must match `bvh::MAX_DEPTH`
+const BVH_STACK_SIZE: u32 = 32u;
+
+// Primitive Kinds, must match `data::Primitive`
+const PRIMITIVE_SPHERE: u32 = 0u;
+const PRIMITIVE_DISK: u32 = 1u;
+const PRIMITIVE_RECTANGLE: u32 = 2u;
+const PRIMITIVE_CYLINDER: u32 = 3u;
 
 // Mesh Types
 struct Object {
@@ -19,6 +32,20 @@ struct Object {
     
     mat: u32,
     mesh: u32,
+    primitive: u32,
+    emissive: u32,
+}
+
+struct Primitive {
+    size: vec2<f32>,
+    kind: u32,
+}
+
+struct Emissive {
+    object: u32,
+    cdf_head: u32,
+    area: f32,
+    power_cdf: f32,
 }
 
 struct Mesh {
@@ -27,13 +54,22 @@ struct Mesh {
     
     ihead: u32,
     vhead: u32,
+    nhead: u32,
     tri_count: u32,
 }
 
+struct BvhNode {
+    aabb_min: vec3<f32>,
+    first: u32,
+    aabb_max: vec3<f32>,
+    count: u32,
+}
+
 struct Vertex {
     position: vec3<f32>,
     normal: vec3<f32>,
     uv: vec2<f32>,
+    tangent: vec4<f32>,
 }
 
 // Ray Types
@@ -46,7 +82,13 @@ struct HitRecord {
     t: f32,
     p: vec3<f32>,
     n: vec3<f32>,
+    // Geometric normal, facing the side the triangle is hit from
+    ng: vec3<f32>,
     uv: vec2<f32>,
+    // Zero when the mesh has no tangents
+    tangent: vec4<f32>,
+    // UV area per world space area of the hit triangle
+    uv_density: f32,
 }
 
 var<private> hit_record: HitRecord;
@@ -54,94 +96,401 @@ var<private> hit_record: HitRecord;
 // Functions
 fn hit_all(ray: Ray) -> u32 {
     var hit = U32_MAX;
-    for (var o = 0u; o < arrayLength(&objects); o++) {
-        if hit_mesh(o, T_MIN, ray) {
-            hit = o;
+
+    // An empty hierarchy is a single interior node pointing at itself
+    if tlas[0].count == 0u && tlas[0].first == 0u {
+        return hit;
+    }
+
+    let inv_dir = 1.0 / ray.dir;
+
+    // TLAS Traversal
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0u;
+    var node_index = 0u;
+    if hit_box(tlas[0].aabb_min, tlas[0].aabb_max, ray.pos, inv_dir) >= hit_record.t {
+        return hit;
+    }
+
+    loop {
+        let node = tlas[node_index];
+        if node.count > 0u {
+            for (var o = node.first; o < node.first + node.count; o++) {
+                if objects[o].primitive != U32_MAX {
+                    if hit_primitive(o, T_MIN, ray) {
+                        hit = o;
+                    }
+                } else if hit_mesh(o, T_MIN, ray) {
+                    hit = o;
+                }
+            }
+        } else {
+            // Visit the nearest child first and defer the other
+            var near = node.first;
+            var far = near + 1u;
+            var t_near = hit_box(tlas[near].aabb_min, tlas[near].aabb_max, ray.pos, inv_dir);
+            var t_far = hit_box(tlas[far].aabb_min, tlas[far].aabb_max, ray.pos, inv_dir);
+            if t_far < t_near {
+                let tmp = near;
+                near = far;
+                far = tmp;
+
+                let t_tmp = t_near;
+                t_near = t_far;
+                t_far = t_tmp;
+            }
+
+            if t_far < hit_record.t {
+                stack[stack_size] = far;
+                stack_size++;
+            }
+            if t_near < hit_record.t {
+                node_index = near;
+                continue;
+            }
+        }
+
+        if stack_size == 0u {
+            break;
         }
+        stack_size--;
+        node_index = stack[stack_size];
     }
 
     return hit;
 }
 
+// Whether anything along `ray` is closer than `t_max`, `hit_record` is left untouched
+fn hit_any(ray: Ray, t_max: f32) -> bool {
+    let record = hit_record;
+    hit_record.t = t_max;
+    let hit = hit_all(ray);
+    hit_record = record;
+    return hit != U32_MAX;
+}
+
 fn hit_mesh(object_index: u32, t_min: f32, _ray: Ray) -> bool {
     let object = &objects[object_index];
     let mesh = &meshes[(*object).mesh];
     var hit = false;
+    if (*mesh).tri_count == 0u {
+        return false;
+    }
 
     // Ray World to Local space
     var ray = _ray;
     ray.pos = ((*object).world_to_local * vec4<f32>(ray.pos, 1.0)).xyz;
     ray.dir = ((*object).world_to_local * vec4<f32>(ray.dir, 0.0)).xyz;
 
-    // Ray-Box Test
-    let t_aabb = hit_box((*mesh).aabb_min, (*mesh).aabb_max, t_min, ray);
-    if t_aabb < t_min {
+    let inv_dir = 1.0 / ray.dir;
+
+    // BVH Traversal
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0u;
+    var node_index = (*mesh).nhead;
+    if hit_box(nodes[node_index].aabb_min, nodes[node_index].aabb_max, ray.pos, inv_dir) >= hit_record.t {
         return false;
     }
-    
-    // Ray-Triangle tests
-    for (var i = 0u; i < (*mesh).tri_count; i++) {
-        let i = i * 3;
 
-        let ai = indices[(*mesh).ihead + i];
-        let bi = indices[(*mesh).ihead + i + 1];
-        let ci = indices[(*mesh).ihead + i + 2];
+    loop {
+        let node = nodes[node_index];
+        if node.count > 0u {
+            // Ray-Triangle tests
+            for (var tri = node.first; tri < node.first + node.count; tri++) {
+                let i = tri * 3;
+
+                let ai = indices[(*mesh).ihead + i];
+                let bi = indices[(*mesh).ihead + i + 1];
+                let ci = indices[(*mesh).ihead + i + 2];
 
-        var va = vertices[(*mesh).vhead + ai];
-        var vb = vertices[(*mesh).vhead + bi];
-        var vc = vertices[(*mesh).vhead + ci];
+                var va = vertices[(*mesh).vhead + ai];
+                var vb = vertices[(*mesh).vhead + bi];
+                var vc = vertices[(*mesh).vhead + ci];
         
-        // Möller–Trumbore
-        let edge_ab = vb.position - va.position;
-        let edge_ac = vc.position - va.position;
-        let n = cross(edge_ab, edge_ac);
-        let ao = ray.pos - va.position;
-        let dao = cross(ao, ray.dir);
-
-        let det = dot(-ray.dir, n);
-        let inv_det = 1.0 / det;
-
-        let t = dot(ao, n) * inv_det;
-        let u = dot(edge_ac, dao) * inv_det;
-        let v = dot(-edge_ab, dao) * inv_det;
-        let w = 1.0 - u - v;
-
-        if det < EPSILON || t < t_min || t > hit_record.t || u < 0.0 || v < 0.0 || w < 0.0 {
-            continue;
-        }
-
-        let _p = ray.pos + ray.dir * t;
-        let _n = va.normal * w + vb.normal * u + vc.normal * v;
-        let _uv = va.uv * w + vb.uv * u + vc.uv * v;
-
-        hit_record.t = t;
-        hit_record.p = ((*object).local_to_world * vec4<f32>(_p, 1.0)).xyz;
-        hit_record.n = normalize( ((*object).local_to_world * vec4<f32>(_n, 0.0)).xyz );
-        hit_record.uv = _uv;
-        hit = true;
+                // Möller–Trumbore
+                let edge_ab = vb.position - va.position;
+                let edge_ac = vc.position - va.position;
+                let n = cross(edge_ab, edge_ac);
+                let ao = ray.pos - va.position;
+                let dao = cross(ao, ray.dir);
+
+                let det = dot(-ray.dir, n);
+                let inv_det = 1.0 / det;
+
+                let t = dot(ao, n) * inv_det;
+                let u = dot(edge_ac, dao) * inv_det;
+                let v = dot(-edge_ab, dao) * inv_det;
+                let w = 1.0 - u - v;
+
+                if det < EPSILON || t < t_min || t > hit_record.t || u < 0.0 || v < 0.0 || w < 0.0 {
+                    continue;
+                }
+
+                let _p = ray.pos + ray.dir * t;
+                let _n = va.normal * w + vb.normal * u + vc.normal * v;
+                let _uv = va.uv * w + vb.uv * u + vc.uv * v;
+                let _t = va.tangent * w + vb.tangent * u + vc.tangent * v;
+
+                hit_record.t = t;
+                hit_record.p = ((*object).local_to_world * vec4<f32>(_p, 1.0)).xyz;
+                hit_record.n = normalize( ((*object).local_to_world * vec4<f32>(_n, 0.0)).xyz );
+                hit_record.ng = normalize((transpose((*object).world_to_local) * vec4<f32>(n, 0.0)).xyz);
+                hit_record.uv = _uv;
+                hit_record.tangent = vec4<f32>(((*object).local_to_world * vec4<f32>(_t.xyz, 0.0)).xyz, _t.w);
+
+                let world_ab = ((*object).local_to_world * vec4<f32>(edge_ab, 0.0)).xyz;
+                let world_ac = ((*object).local_to_world * vec4<f32>(edge_ac, 0.0)).xyz;
+                let uv_ab = vb.uv - va.uv;
+                let uv_ac = vc.uv - va.uv;
+                let uv_area = abs(uv_ab.x * uv_ac.y - uv_ab.y * uv_ac.x);
+                hit_record.uv_density = uv_area / max(length(cross(world_ab, world_ac)), EPSILON);
+                hit = true;
+            }
+        } else {
+            // Visit the nearest child first and defer the other
+            var near = (*mesh).nhead + node.first;
+            var far = near + 1u;
+            var t_near = hit_box(nodes[near].aabb_min, nodes[near].aabb_max, ray.pos, inv_dir);
+            var t_far = hit_box(nodes[far].aabb_min, nodes[far].aabb_max, ray.pos, inv_dir);
+            if t_far < t_near {
+                let tmp = near;
+                near = far;
+                far = tmp;
+
+                let t_tmp = t_near;
+                t_near = t_far;
+                t_far = t_tmp;
+            }
+
+            if t_far < hit_record.t {
+                stack[stack_size] = far;
+                stack_size++;
+            }
+            if t_near < hit_record.t {
+                node_index = near;
+                continue;
+            }
+        }
+
+        if stack_size == 0u {
+            break;
+        }
+        stack_size--;
+        node_index = stack[stack_size];
     }
 
     return hit;
 }
 
-fn hit_box(min: vec3<f32>, max: vec3<f32>, _tmin: f32, ray: Ray) -> f32 {
-    let inv_dir = 1.0 / ray.dir;
-    var tmin = (min - ray.pos) * inv_dir;
-    var tmax = (max - ray.pos) * inv_dir;
+fn hit_primitive(object_index: u32, t_min: f32, _ray: Ray) -> bool {
+    let object = &objects[object_index];
+    let primitive = primitives[(*object).primitive];
+
+    // Ray World to Local space, `dir` isn't normalized so `t` is the same in both
+    var ray = _ray;
+    ray.pos = ((*object).world_to_local * vec4<f32>(ray.pos, 1.0)).xyz;
+    ray.dir = ((*object).world_to_local * vec4<f32>(ray.dir, 0.0)).xyz;
+
+    // Only the outside is hit, primitives are one sided like triangles
+    var t = INFINITY;
+    switch primitive.kind {
+        case PRIMITIVE_SPHERE: {
+            t = hit_sphere(ray, primitive.size.x);
+        }
+        case PRIMITIVE_DISK: {
+            let t_plane = -ray.pos.z / ray.dir.z;
+            let p = ray.pos + ray.dir * t_plane;
+            if ray.dir.z < 0.0 && dot(p.xy, p.xy) <= primitive.size.x * primitive.size.x {
+                t = t_plane;
+            }
+        }
+        case PRIMITIVE_RECTANGLE: {
+            let t_plane = -ray.pos.z / ray.dir.z;
+            let p = ray.pos + ray.dir * t_plane;
+            if ray.dir.z < 0.0 && all(abs(p.xy) <= primitive.size) {
+                t = t_plane;
+            }
+        }
+        case PRIMITIVE_CYLINDER: {
+            t = hit_cylinder(ray, primitive.size.x, primitive.size.y);
+        }
+        default: {}
+    }
+
+    if t < t_min || t > hit_record.t {
+        return false;
+    }
+
+    var p = ray.pos + ray.dir * t;
+    if primitive.kind == PRIMITIVE_CYLINDER && abs(p.y) > primitive.size.y * (1.0 - EPSILON) {
+        // Snapped so `primitive_surface` sees a cap
+        p.y = sign(p.y) * primitive.size.y;
+    }
+    let surface = primitive_surface(primitive, p);
+
+    // Normals are transformed by the inverse transpose, whose length is also how much
+    // the transform stretches area along the surface
+    let world_to_local = mat3x3<f32>((*object).world_to_local[0].xyz, (*object).world_to_local[1].xyz, (*object).world_to_local[2].xyz);
+    let n = transpose(world_to_local) * surface.n;
+    let area_scale = length(n) / abs(determinant(world_to_local));
+
+    hit_record.t = t;
+    hit_record.p = ((*object).local_to_world * vec4<f32>(p, 1.0)).xyz;
+    hit_record.n = normalize(n);
+    hit_record.ng = hit_record.n;
+    hit_record.uv = surface.uv;
+    hit_record.tangent = vec4<f32>(((*object).local_to_world * vec4<f32>(surface.tangent.xyz, 0.0)).xyz, surface.tangent.w);
+    hit_record.uv_density = surface.uv_density / max(area_scale, EPSILON);
+    return true;
+}
+
+// Nearest intersection from outside of a sphere at the origin, INFINITY when there is none
+fn hit_sphere(ray: Ray, radius: f32) -> f32 {
+    let a = dot(ray.dir, ray.dir);
+    let b = dot(ray.pos, ray.dir);
+    let c = dot(ray.pos, ray.pos) - radius * radius;
+    let discriminant = b * b - a * c;
+    if c < 0.0 || discriminant < 0.0 {
+        return INFINITY;
+    }
+    return (-b - sqrt(discriminant)) / a;
+}
+
+// Nearest intersection from outside of a capped cylinder along Y, INFINITY when there is none
+fn hit_cylinder(ray: Ray, radius: f32, half_height: f32) -> f32 {
+    // Side
+    let a = dot(ray.dir.xz, ray.dir.xz);
+    let b = dot(ray.pos.xz, ray.dir.xz);
+    let c = dot(ray.pos.xz, ray.pos.xz) - radius * radius;
+    let discriminant = b * b - a * c;
+    if c >= 0.0 && discriminant >= 0.0 && a > 0.0 {
+        let t = (-b - sqrt(discriminant)) / a;
+        if abs(ray.pos.y + ray.dir.y * t) < half_height {
+            return t;
+        }
+    }
+
+    // Caps, entered from above the top or below the bottom
+    if ray.dir.y != 0.0 && abs(ray.pos.y) >= half_height {
+        let t = (sign(ray.pos.y) * half_height - ray.pos.y) / ray.dir.y;
+        let p = ray.pos.xz + ray.dir.xz * t;
+        if ray.pos.y * ray.dir.y < 0.0 && dot(p, p) <= radius * radius {
+            return t;
+        }
+    }
+
+    return INFINITY;
+}
+
+// Local space shading frame of a primitive at `p`, which has to lie on its surface
+struct PrimitiveSurface {
+    n: vec3<f32>,
+    uv: vec2<f32>,
+    tangent: vec4<f32>,
+    // UV area per local space area
+    uv_density: f32,
+}
+
+fn primitive_surface(primitive: Primitive, p: vec3<f32>) -> PrimitiveSurface {
+    let size = primitive.size;
+    switch primitive.kind {
+        case PRIMITIVE_SPHERE: {
+            // U around Y and V from the top pole down
+            let n = p / size.x;
+            let phi = atan2(-n.z, n.x);
+            let ring = max(length(p.xz), EPSILON);
+            return PrimitiveSurface(
+                n,
+                vec2<f32>(fract(phi / PI_2 + 1.0), acos(clamp(n.y, -1.0, 1.0)) / PI),
+                vec4<f32>(p.z / ring, 0.0, -p.x / ring, 1.0),
+                1.0 / (PI_2 * PI * size.x * ring),
+            );
+        }
+        case PRIMITIVE_CYLINDER: {
+            if abs(p.y) >= size.y {
+                // Caps, mapped like disks seen from outside
+                let n = vec3<f32>(0.0, sign(p.y), 0.0);
+                return PrimitiveSurface(
+                    n,
+                    vec2<f32>(p.x, p.z * n.y) / (2.0 * size.x) + 0.5,
+                    vec4<f32>(1.0, 0.0, 0.0, 1.0),
+                    1.0 / (4.0 * size.x * size.x),
+                );
+            }
+
+            let n = vec3<f32>(p.x, 0.0, p.z) / size.x;
+            let phi = atan2(-n.z, n.x);
+            return PrimitiveSurface(
+                n,
+                vec2<f32>(fract(phi / PI_2 + 1.0), (size.y - p.y) / (2.0 * size.y)),
+                vec4<f32>(n.z, 0.0, -n.x, 1.0),
+                1.0 / (PI_2 * size.x * 2.0 * size.y),
+            );
+        }
+        default: {
+            // Disks and rectangles
+            var half_size = size;
+            if primitive.kind == PRIMITIVE_DISK {
+                half_size = vec2<f32>(size.x);
+            }
+            return PrimitiveSurface(
+                vec3<f32>(0.0, 0.0, 1.0),
+                vec2<f32>(p.x, -p.y) / (2.0 * half_size) + 0.5,
+                vec4<f32>(1.0, 0.0, 0.0, 1.0),
+                1.0 / (4.0 * half_size.x * half_size.y),
+            );
+        }
+    }
+}
+
+// Uniform point by local area on the surface of a primitive
+fn sample_primitive(primitive: Primitive, rng: vec3<f32>) -> vec3<f32> {
+    let size = primitive.size;
+    let phi = PI_2 * rng.y;
+    switch primitive.kind {
+        case PRIMITIVE_SPHERE: {
+            let y = 1.0 - 2.0 * rng.x;
+            let ring = sqrt(max(1.0 - y * y, 0.0));
+            return vec3<f32>(ring * cos(phi), y, -ring * sin(phi)) * size.x;
+        }
+        case PRIMITIVE_DISK: {
+            let r = size.x * sqrt(rng.x);
+            return vec3<f32>(r * cos(phi), r * sin(phi), 0.0);
+        }
+        case PRIMITIVE_RECTANGLE: {
+            return vec3<f32>((rng.xy * 2.0 - 1.0) * size, 0.0);
+        }
+        case PRIMITIVE_CYLINDER: {
+            // Side or either cap by area
+            let side = 2.0 * size.y;
+            let s = rng.z * (side + size.x);
+            if s < side {
+                return vec3<f32>(size.x * cos(phi), (rng.x * 2.0 - 1.0) * size.y, -size.x * sin(phi));
+            }
+            let r = size.x * sqrt(rng.x);
+            let y = select(-size.y, size.y, s < side + size.x * 0.5);
+            return vec3<f32>(r * cos(phi), y, -r * sin(phi));
+        }
+        default: {
+            return vec3<f32>(0.0);
+        }
+    }
+}
+
+// Returns the distance to the box or INFINITY when it is missed
+fn hit_box(min: vec3<f32>, max: vec3<f32>, pos: vec3<f32>, inv_dir: vec3<f32>) -> f32 {
+    let tmin = (min - pos) * inv_dir;
+    let tmax = (max - pos) * inv_dir;
     
     let t1 = min(tmin, tmax);
     let t2 = max(tmin, tmax);
     let dst_near = max(max(t1.x, t1.y), t1.z);
     let dst_far = min(min(t2.x, t2.y), t2.z);
 
-    let hit = dst_far >= dst_near && dst_far > 0;
+    let hit = dst_far >= dst_near && dst_far > 0.0;
     if hit {
-        if dst_near > 0.0 {
-            return dst_near;
-        } else {
-            return INFINITY;
-        }
+        return max(dst_near, 0.0);
     } else {
-        return 0.0;
+        return INFINITY;
     }
 }
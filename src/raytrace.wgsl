@@ -3,20 +3,30 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import bevy_render::{view::View, globals::Globals}
 #import bevy_render::maths::{PI, HALF_PI}
-#import bevy_pbr::lighting;
-#import bevy_pbr::pbr_functions;
 
 #import path_tracing::math::{EPSILON, U32_MAX}
 
 @group(0) @binding(0) var<uniform> view: View;
 @group(0) @binding(1) var<uniform> globals: Globals;
 @group(0) @binding(2) var<uniform> settings: Settings;
+@group(0) @binding(3) var accumulation: texture_2d<f32>;
 
-#import path_tracing::query::{Ray, HitRecord, hit_record, hit_all, objects};
+#import path_tracing::query::{
+    Ray, HitRecord, hit_record, hit_all, hit_any,
+    objects, emissives, emissive_cdf, meshes, indices, vertices,
+    primitives, primitive_surface, sample_primitive,
+};
+#import path_tracing::lights::{lights, sample_light};
+#import path_tracing::bsdf;
+#import path_tracing::bsdf::{Surface, BsdfSample};
 
 @group(3) @binding(0) var<storage> materials: array<Material>;
 @group(3) @binding(1) var<storage> textures: array<Texture>;
-@group(3) @binding(2) var<storage> texture_data: array<f32>;
+@group(3) @binding(2) var<storage> texture_data: array<u32>;
+#ifdef TEXTURE_BINDING_ARRAY
+@group(3) @binding(3) var texture_array: binding_array<texture_2d<f32>, #{TEXTURE_BINDING_ARRAY_SIZE}>;
+@group(3) @binding(4) var texture_samplers: binding_array<sampler, #{TEXTURE_BINDING_ARRAY_SIZE}>;
+#endif
 
 // ---- Binding Data ----
 
@@ -31,25 +41,59 @@ struct Material {
     albedo_texture: u32,
     emissive: vec3<f32>,
     emissive_texture: u32,
+    emissive_exposure_weight: f32,
     roughness: f32,
     metallic: f32,
     metallic_roughness_texture: u32,
     reflectance: f32,
     normal_map_texture: u32,
+    flags: u32,
 }
 
+// Material Flags, must match `data::Material`
+const FLIP_NORMAL_MAP_Y: u32 = 1u;
+
 struct Texture {
     width: u32,
     height: u32,
     offset: u32,
     format: u32,
+    address_mode_u: u32,
+    address_mode_v: u32,
+    filter_mode: u32,
+    mip_levels: u32,
 }
 
+// Texture sampler, must match `data::Texture`
+const ADDRESS_REPEAT: u32 = 1u;
+const ADDRESS_MIRROR: u32 = 2u;
+
+const FILTER_MAG_LINEAR: u32 = 1u;
+const FILTER_MIN_LINEAR: u32 = 2u;
+const FILTER_MIPMAP_LINEAR: u32 = 4u;
+
+const FORMAT_R8: u32 = 1u;
+const FORMAT_RG8: u32 = 2u;
+const FORMAT_RGBA8: u32 = 3u;
+const FORMAT_RGBA16F: u32 = 4u;
+const FORMAT_RGB9E5: u32 = 5u;
+const FORMAT_RGBA32F: u32 = 6u;
+const FORMAT_RGBA8_SRGB: u32 = 7u;
+
 // --- Runtime Data ----
 
-struct BRDFOutput {
-    ray_dir: vec3<f32>,
-    color: vec3<f32>,
+struct EmissiveSample {
+    p: vec3<f32>,
+    n: vec3<f32>,
+    emission: vec3<f32>,
+    // Density per unit area
+    pdf: f32,
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // Running sum of every sample in rgb and the sample count in alpha
+    @location(1) accumulation: vec4<f32>,
 }
 
 var<private> rng_state: vec3<u32>;
@@ -85,146 +129,419 @@ fn rand_unit() -> vec3<f32> {
     return normalize(vec3<f32>(pcg3d()) / 3141.592653589793);
 }
 
-fn cosine_sample() -> vec3<f32> {
-    let rng = rand();
-    let phi = 2 * PI * rng.x;
-    let sqr_sin_theta = rng.y;
-    let sin_theta = sqrt(sqr_sin_theta);
-    let cos_theta = sqrt(1.0 - sqr_sin_theta);
-    return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
-}
-
 fn rng_setup(uv: vec2<f32>) {
     rng_state = vec3<u32>(u32(uv.x), u32(uv.y), u32(uv.x) ^ u32(uv.y));
 }
 
-// ---- Helper ----
-fn hugues_moller(n: vec3<f32>) -> mat3x3<f32> {
-    let a = abs(n);
-    var t = vec3<f32>(0);
-    if a.x <= a.y && a.x <= a.z {
-        t = vec3<f32>(0, -n.z, n.y);
-    } else if a.y <= a.x && a.y <= a.z {
-        t = vec3<f32>(-n.z, 0, n.x);
+// ---- Texture ----
+
+// `footprint` is the width of the ray cone in UV space and picks the mip level
+#ifdef TEXTURE_BINDING_ARRAY
+fn sample_texture(idx: u32, uv: vec2<f32>, footprint: f32) -> vec3<f32> {
+    let texture = textures[idx];
+    let lod = log2(footprint * sqrt(f32(texture.width * texture.height)));
+    let color = textureSampleLevel(texture_array[idx], texture_samplers[idx], uv, max(lod, 0.0));
+    return color.rgb * color.a;
+}
+#else
+// Mirrored by `TextureData::sample`, changes have to be made to both
+fn sample_texture(idx: u32, uv: vec2<f32>, footprint: f32) -> vec3<f32> {
+    let texture = textures[idx];
+    if texture.format == 0u || texture.format > FORMAT_RGBA8_SRGB {
+        return vec3<f32>(1.0);
+    }
+
+    let lod = log2(footprint * sqrt(f32(texture.width * texture.height)));
+    var color: vec4<f32>;
+    if lod <= 0.0 || texture.mip_levels == 1u {
+        color = sample_level(texture, uv, 0u, (texture.filter_mode & FILTER_MAG_LINEAR) != 0u);
     } else {
-        t = vec3<f32>(-n.y, n.x, 0);
+        let linear = (texture.filter_mode & FILTER_MIN_LINEAR) != 0u;
+        let level = min(lod, f32(texture.mip_levels - 1u));
+        if (texture.filter_mode & FILTER_MIPMAP_LINEAR) != 0u {
+            let lower = u32(floor(level));
+            let upper = min(lower + 1u, texture.mip_levels - 1u);
+            color = mix(
+                sample_level(texture, uv, lower, linear),
+                sample_level(texture, uv, upper, linear),
+                fract(level),
+            );
+        } else {
+            color = sample_level(texture, uv, u32(round(level)), linear);
+        }
     }
-    t = normalize(t);
-                    
-    let b = normalize(cross(n, t));
-    return mat3x3<f32>(t, b, n);
+
+    return color.rgb * color.a;
 }
 
-// ---- Texture ----
+fn sample_level(texture: Texture, uv: vec2<f32>, level: u32, linear: bool) -> vec4<f32> {
+    var offset = texture.offset;
+    var size = vec2<u32>(texture.width, texture.height);
+    for (var i = 0u; i < level; i++) {
+        offset += texture_words(texture.format, size.x * size.y);
+        size = max(size / 2u, vec2<u32>(1u));
+    }
 
-fn sample_texture(idx: u32, u: f32, v: f32) -> vec3<f32> {
-    let texture = textures[idx];
-    let x = u * f32(texture.width);
-    let y = v * f32(texture.height);
-    let i = texture.offset + (u32(x) + u32(y) * texture.height) * texture.format;
+    let texel = uv * vec2<f32>(size);
+    if !linear {
+        return load_texel(texture, offset, size, vec2<i32>(floor(texel)));
+    }
+
+    // Bilinear between the four closest texel centers
+    let p = texel - 0.5;
+    let base = vec2<i32>(floor(p));
+    let f = fract(p);
+    let a = load_texel(texture, offset, size, base);
+    let b = load_texel(texture, offset, size, base + vec2<i32>(1, 0));
+    let c = load_texel(texture, offset, size, base + vec2<i32>(0, 1));
+    let d = load_texel(texture, offset, size, base + vec2<i32>(1, 1));
+    return mix(mix(a, b, f.x), mix(c, d, f.x), f.y);
+}
+
+fn load_texel(texture: Texture, offset: u32, size: vec2<u32>, texel: vec2<i32>) -> vec4<f32> {
+    let x = address(texel.x, i32(size.x), texture.address_mode_u);
+    let y = address(texel.y, i32(size.y), texture.address_mode_v);
+    let t = x + y * size.x;
 
     switch (texture.format) {
-        case 1u: {
-            let r = texture_data[i];
-            return vec3<f32>(r);
+        case FORMAT_R8: {
+            let word = texture_data[offset + t / 4u];
+            return vec4<f32>(vec3<f32>(unpack4x8unorm(word >> ((t % 4u) * 8u)).x), 1.0);
+        }
+        case FORMAT_RG8: {
+            let word = texture_data[offset + t / 2u];
+            return vec4<f32>(unpack4x8unorm(word >> ((t % 2u) * 16u)).xy, 0.0, 1.0);
         }
-        case 2u: {
-            let r = texture_data[i];
-            let g = texture_data[i + 1];
-            return vec3<f32>(r, g, 0.0);
+        case FORMAT_RGBA8: {
+            return unpack4x8unorm(texture_data[offset + t]);
         }
-        case 3u: {
-            let r = texture_data[i];
-            let g = texture_data[i + 1];
-            let b = texture_data[i + 2];
-            return vec3<f32>(r, g, b);
+        case FORMAT_RGBA8_SRGB: {
+            let color = unpack4x8unorm(texture_data[offset + t]);
+            return vec4<f32>(srgb_to_linear(color.rgb), color.a);
         }
-        case 4u: {
-            let r = texture_data[i];
-            let g = texture_data[i + 1];
-            let b = texture_data[i + 2];
-            let a = texture_data[i + 3];
-            return vec3<f32>(r, g, b) * a;
+        case FORMAT_RGBA16F: {
+            let rg = unpack2x16float(texture_data[offset + t * 2u]);
+            let ba = unpack2x16float(texture_data[offset + t * 2u + 1u]);
+            return vec4<f32>(rg, ba);
+        }
+        case FORMAT_RGB9E5: {
+            return vec4<f32>(decode_rgb9e5(texture_data[offset + t]), 1.0);
         }
         default: {
-            return vec3<f32>(1.0);
+            let i = offset + t * 4u;
+            return bitcast<vec4<f32>>(vec4<u32>(
+                texture_data[i],
+                texture_data[i + 1u],
+                texture_data[i + 2u],
+                texture_data[i + 3u],
+            ));
         }
     }
 }
 
-// ---- BRDF ----
+// Shared exponent float, 9 bit mantissas with a bias of 15
+fn decode_rgb9e5(word: u32) -> vec3<f32> {
+    let exponent = i32(word >> 27u) - 15 - 9;
+    let mantissa = vec3<u32>(word, word >> 9u, word >> 18u) & vec3<u32>(0x1ffu);
+    return vec3<f32>(mantissa) * exp2(f32(exponent));
+}
 
-fn calculate_brdf(ray: Ray, material: Material) -> BRDFOutput {
-    let lambertian_ray = normalize(hugues_moller(hit_record.n) * cosine_sample()); // Lambertian
-    let reflection_ray = normalize(reflect(ray.dir, hit_record.n)); // Reflection
-    let new_ray_dir = mix(reflection_ray, lambertian_ray, material.roughness);
+// Same curve as Bevy's `Srgba::gamma_function`
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let linear = color / 12.92;
+    let gamma = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(gamma, linear, color <= vec3<f32>(0.04045));
+}
+
+// Words taken by `texels` texels, every level starts on a new word
+fn texture_words(format: u32, texels: u32) -> u32 {
+    switch (format) {
+        case FORMAT_R8: {
+            return (texels + 3u) / 4u;
+        }
+        case FORMAT_RG8: {
+            return (texels + 1u) / 2u;
+        }
+        case FORMAT_RGBA16F: {
+            return texels * 2u;
+        }
+        case FORMAT_RGBA32F: {
+            return texels * 4u;
+        }
+        default: {
+            return texels;
+        }
+    }
+}
+
+// Wraps a texel coordinate into `0..size`
+fn address(i: i32, size: i32, mode: u32) -> u32 {
+    switch (mode) {
+        case ADDRESS_REPEAT: {
+            return u32(((i % size) + size) % size);
+        }
+        case ADDRESS_MIRROR: {
+            let period = size * 2;
+            let m = ((i % period) + period) % period;
+            return u32(select(m, period - 1 - m, m >= size));
+        }
+        default: {
+            return u32(clamp(i, 0, size - 1));
+        }
+    }
+}
+#endif
+
+// ---- Normal ----
+
+// Same as Bevy's `apply_normal_mapping` with a MikkTSpace frame
+fn apply_normal_map(material: Material, footprint: f32) {
+    let tangent = hit_record.tangent;
+    if all(tangent.xyz == vec3<f32>(0.0)) {
+        return;
+    }
+
+    let N = hit_record.n;
+    let T = tangent.xyz;
+    let B = tangent.w * cross(N, T);
+
+    var Nt = sample_texture(material.normal_map_texture, hit_record.uv, footprint);
+    if textures[material.normal_map_texture].format == FORMAT_RG8 {
+        // Two component maps only store x and y
+        Nt = vec3<f32>(Nt.xy * 2.0 - 1.0, 0.0);
+        Nt.z = sqrt(max(1.0 - Nt.x * Nt.x - Nt.y * Nt.y, 0.0));
+    } else {
+        Nt = Nt * 2.0 - 1.0;
+    }
+    if (material.flags & FLIP_NORMAL_MAP_Y) != 0u {
+        Nt.y = -Nt.y;
+    }
+
+    hit_record.n = normalize(Nt.x * T + Nt.y * B + Nt.z * N);
+}
+
+// ---- BRDF ----
 
+fn get_surface(material: Material, footprint: f32) -> Surface {
     var albedo = material.albedo;
     var metallic = material.metallic;
     var roughness = material.roughness;
     if material.albedo_texture != U32_MAX {
-        albedo *= sample_texture(material.albedo_texture, hit_record.uv.x, hit_record.uv.y);
+        albedo *= sample_texture(material.albedo_texture, hit_record.uv, footprint);
     }
     if material.metallic_roughness_texture != U32_MAX {
-        let mr = sample_texture(material.metallic_roughness_texture, hit_record.uv.x, hit_record.uv.y);
+        // glTF convention, roughness in green and metallic in blue
+        let mr = sample_texture(material.metallic_roughness_texture, hit_record.uv, footprint);
+        roughness *= mr.g;
+        metallic *= mr.b;
     }
-    
-    
-    // BRDF Vectors
-    let N = hit_record.n; // Surface Normal
-    let V = -ray.dir; // View Vector (Outgoing Light)
-    let L = new_ray_dir; // Incoming Light
-    let R = reflect(-L, N); // reflection vector
-
-    // Dot Products
-    let NdotL = dot(N, L);
-    let NdotV = max(dot(N, V), 0.0001);
-
-    // Create Lighting Input
-    var lighting_input: lighting::LightingInput;
-    lighting_input.layers[lighting::LAYER_BASE].NdotV = NdotV;
-    lighting_input.layers[lighting::LAYER_BASE].N = N;
-    lighting_input.layers[lighting::LAYER_BASE].R = R;
-    lighting_input.layers[lighting::LAYER_BASE].perceptual_roughness = roughness;
-    lighting_input.layers[lighting::LAYER_BASE].roughness = lighting::perceptualRoughnessToRoughness(roughness);
-    lighting_input.P = hit_record.p;
-    lighting_input.V = V;
-    lighting_input.diffuse_color = albedo;
-    lighting_input.F0_ = pbr_functions::calculate_F0(albedo, metallic, material.reflectance);
-    lighting_input.F_ab = lighting::F_AB(roughness, NdotV);
-
-    var derived_lighting_input = lighting::derive_lighting_input(N, V, L);
-
-    // let specular = lighting::specular(&lighting_input, &derived_lighting_input, material.reflectance);
-    let color = albedo * lighting::Fd_Burley(&lighting_input, &derived_lighting_input);
 
-    // Output
-    return BRDFOutput(new_ray_dir, color * PI);
+    return Surface(albedo, metallic, roughness, material.reflectance);
+}
+
+fn calculate_brdf(ray: Ray, surface: Surface) -> BsdfSample {
+    return bsdf::sample(surface, hit_record.n, -ray.dir, rand());
+}
+
+// Density `calculate_brdf` samples `L` with
+fn brdf_pdf(V: vec3<f32>, L: vec3<f32>, surface: Surface) -> f32 {
+    return bsdf::pdf(surface, hit_record.n, V, L);
+}
+
+// BRDF times the cosine term for light arriving from `L`
+fn evaluate_brdf(V: vec3<f32>, L: vec3<f32>, surface: Surface) -> vec3<f32> {
+    return bsdf::evaluate(surface, hit_record.n, V, L);
+}
+
+// ---- Lights ----
+
+// Light reaching the current hit from Bevy's punctual lights towards `V`
+fn direct_lighting(V: vec3<f32>, surface: Surface) -> vec3<f32> {
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < arrayLength(&lights); i++) {
+        // The buffer always holds at least one, possibly zeroed, light
+        let light = lights[i];
+        if all(light.color == vec3<f32>(0.0)) {
+            continue;
+        }
+
+        let sample = sample_light(light, hit_record.p, rand());
+        let f = evaluate_brdf(V, sample.dir, surface);
+        if all(f == vec3<f32>(0.0)) {
+            continue;
+        }
+
+        let shadow_ray = Ray(hit_record.p + sample.dir * 0.001, sample.dir);
+        if hit_any(shadow_ray, sample.distance - 0.001) {
+            continue;
+        }
+
+        // Bevy's rasterizer scales lights by the camera exposure, emissives get theirs in `get_emission`
+        color += f * sample.radiance * view.exposure;
+    }
+
+    return color;
+}
+
+// ---- Emissives ----
+
+// Exposed by `emissive_exposure_weight` of the camera exposure, same as Bevy's rasterizer
+fn get_emission(material: Material, uv: vec2<f32>, footprint: f32) -> vec3<f32> {
+    var emission = material.emissive;
+    if material.emissive_texture != U32_MAX {
+        emission = sample_texture(material.emissive_texture, uv, footprint);
+    }
+    return emission * mix(1.0, view.exposure, material.emissive_exposure_weight);
+}
+
+fn has_emissives() -> bool {
+    // The buffer always holds at least one, possibly zeroed, emissive
+    return emissives[arrayLength(&emissives) - 1u].power_cdf > 0.0;
+}
+
+// Density per unit area of `sample_emissive` picking a point on the emissive
+fn emissive_pdf(index: u32) -> f32 {
+    let emissive = emissives[index];
+    var pdf = emissive.power_cdf;
+    if index > 0u {
+        pdf -= emissives[index - 1u].power_cdf;
+    }
+    return pdf / emissive.area;
+}
+
+fn sample_emissive() -> EmissiveSample {
+    let rng = rand();
+
+    // Emissive by power
+    var lo = 0u;
+    var hi = arrayLength(&emissives) - 1u;
+    while lo < hi {
+        let mid = (lo + hi) / 2u;
+        if emissives[mid].power_cdf <= rng.x {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    let index = lo;
+    let emissive = emissives[index];
+    let object = objects[emissive.object];
+
+    // Primitive by local area, which is uniform in world space unless a curved one is scaled unevenly
+    if object.primitive != U32_MAX {
+        let primitive = primitives[object.primitive];
+        let p = sample_primitive(primitive, rand());
+        let surface = primitive_surface(primitive, p);
+        return EmissiveSample(
+            (object.local_to_world * vec4<f32>(p, 1.0)).xyz,
+            normalize((transpose(object.world_to_local) * vec4<f32>(surface.n, 0.0)).xyz),
+            get_emission(materials[object.mat], surface.uv, 0.0),
+            emissive_pdf(index),
+        );
+    }
+
+    let mesh = meshes[object.mesh];
+
+    // Triangle by area
+    lo = emissive.cdf_head;
+    hi = emissive.cdf_head + mesh.tri_count - 1u;
+    while lo < hi {
+        let mid = (lo + hi) / 2u;
+        if emissive_cdf[mid] <= rng.y {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    let i = (lo - emissive.cdf_head) * 3u;
+    let va = vertices[mesh.vhead + indices[mesh.ihead + i]];
+    let vb = vertices[mesh.vhead + indices[mesh.ihead + i + 1u]];
+    let vc = vertices[mesh.vhead + indices[mesh.ihead + i + 2u]];
+
+    // Uniform point on the triangle
+    let s = sqrt(rng.z);
+    let t = rand().x;
+    let w = 1.0 - s;
+    let u = s * (1.0 - t);
+    let v = s * t;
+
+    let a = (object.local_to_world * vec4<f32>(va.position, 1.0)).xyz;
+    let b = (object.local_to_world * vec4<f32>(vb.position, 1.0)).xyz;
+    let c = (object.local_to_world * vec4<f32>(vc.position, 1.0)).xyz;
+    let uv = va.uv * w + vb.uv * u + vc.uv * v;
+
+    return EmissiveSample(
+        a * w + b * u + c * v,
+        normalize(cross(b - a, c - a)),
+        get_emission(materials[object.mat], uv, 0.0),
+        emissive_pdf(index),
+    );
+}
+
+// Light reaching the current hit from an emissive surface towards `V`,
+// weighted against `calculate_brdf` finding the same surface
+fn emissive_lighting(V: vec3<f32>, surface: Surface) -> vec3<f32> {
+    let sample = sample_emissive();
+    let to_light = sample.p - hit_record.p;
+    let distance = length(to_light);
+    let L = to_light / distance;
+
+    // Emissives are one sided like every other triangle
+    let cos_light = dot(sample.n, -L);
+    if cos_light <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+
+    let f = evaluate_brdf(V, L, surface);
+    if all(f == vec3<f32>(0.0)) {
+        return vec3<f32>(0.0);
+    }
+
+    let shadow_ray = Ray(hit_record.p + L * 0.001, L);
+    if hit_any(shadow_ray, distance - 0.001) {
+        return vec3<f32>(0.0);
+    }
+
+    let light_pdf = sample.pdf * distance * distance / cos_light;
+    let weight = power_heuristic(light_pdf, brdf_pdf(V, L, surface));
+    return f * sample.emission * weight / light_pdf;
+}
+
+fn power_heuristic(a: f32, b: f32) -> f32 {
+    return a * a / (a * a + b * b);
 }
 
 // ---- Entry ----
 
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
     // Setup
     rng_setup(in.uv * view.viewport.zw * (globals.time + 1.0));
     
-    let d = (in.uv * 2.0 - 1.0) * vec2<f32>(1.0, -1.0);
-
-    // https://github.com/Vecvec/wgpu/blob/ray-tracing-new/examples/src/ray_cube_fragment/shader.wgsl#L60
     let initial_origin = (view.world_from_view * vec4<f32>(0.0, 0.0, 0.0, 1.0)).xyz;
-    let temp = view.view_from_clip * vec4<f32>(d.x, d.y, 1.0, 1.0);
-    let initial_direction = (view.world_from_view * vec4<f32>(normalize(temp.xyz), 0.0)).xyz;
+
+    // Ray cone through one pixel, perspective cameras spread it and orthographic ones give it a width
+    let pixel_size = 2.0 / (view.clip_from_view[1][1] * view.viewport.w);
+    let orthographic = view.clip_from_view[3][3] == 1.0;
     
     // Sample
     var pixel_color = vec3<f32>(0.0);
     for (var sample = 0u; sample < settings.samples; sample++) {
-        // Setup
+        // Setup, jittered inside the pixel so accumulated frames are anti-aliased
+        let uv = in.uv + (rand().xy - 0.5) / view.viewport.zw;
+        let d = (uv * 2.0 - 1.0) * vec2<f32>(1.0, -1.0);
+
+        // https://github.com/Vecvec/wgpu/blob/ray-tracing-new/examples/src/ray_cube_fragment/shader.wgsl#L60
+        let temp = view.view_from_clip * vec4<f32>(d.x, d.y, 1.0, 1.0);
+        let initial_direction = (view.world_from_view * vec4<f32>(normalize(temp.xyz), 0.0)).xyz;
         var ray = Ray(initial_origin, initial_direction);
         
         // Tracing
         var ray_color = vec3<f32>(1.0);
         var color = vec3<f32>(0.0);
+        var last_pdf = 0.0;
+        var cone_width = select(0.0, pixel_size, orthographic);
+        var cone_spread = select(pixel_size, 0.0, orthographic);
 
         for (var bounce = 0u; bounce < settings.bounces; bounce++) {
             hit_record.t = 1000.0;
@@ -235,13 +552,21 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
                 let material = materials[object.mat];
                 let prev_ray_dir = ray.dir;
 
-                // Emissive
-                var emissive = material.emissive;
-                if material.emissive_texture != U32_MAX {
-                    emissive = sample_texture(material.emissive_texture, hit_record.uv.x, hit_record.uv.y);
+                // Texture footprint of the cone, stretched by the angle it hits at
+                cone_width += cone_spread * hit_record.t;
+                let cos_hit = max(abs(dot(hit_record.ng, ray.dir)), EPSILON);
+                let footprint = cone_width * sqrt(hit_record.uv_density) / cos_hit;
+
+                // Emissive, weighted against `emissive_lighting` at the previous hit
+                let emissive = get_emission(material, hit_record.uv, footprint);
+                var weight = 1.0;
+                if last_pdf > 0.0 && object.emissive != U32_MAX {
+                    let cos_light = abs(dot(hit_record.ng, ray.dir));
+                    let light_pdf = emissive_pdf(object.emissive) * hit_record.t * hit_record.t / cos_light;
+                    weight = power_heuristic(last_pdf, light_pdf);
                 }
-                
-                color += ray_color * emissive;
+
+                color += ray_color * emissive * weight;
                 if dot(material.albedo, material.albedo) < EPSILON {
                     // Skip Scatter, BRDF and RayColor
                     break;
@@ -249,15 +574,27 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
 
                 // Normal
                 if material.normal_map_texture != U32_MAX {
-                    hit_record.n *= sample_texture(material.normal_map_texture, hit_record.uv.x, hit_record.uv.y);
+                    apply_normal_map(material, footprint);
+                }
+
+                let surface = get_surface(material, footprint);
+
+                // Lights
+                color += ray_color * direct_lighting(-ray.dir, surface);
+                if has_emissives() {
+                    color += ray_color * emissive_lighting(-ray.dir, surface);
                 }
 
                 // Scatter
-                let brdf = calculate_brdf(ray, material);
-                ray.dir = brdf.ray_dir;
+                let brdf = calculate_brdf(ray, surface);
+                ray.dir = brdf.dir;
                 ray.pos = hit_record.p + ray.dir * 0.001;
 
-                ray_color *= brdf.color;
+                ray_color *= brdf.weight;
+                last_pdf = brdf.pdf;
+
+                // Rough bounces blur whatever they see next, roughly by the lobe's width
+                cone_spread = max(cone_spread, surface.roughness * surface.roughness);
             } else {
                 color += ray_color * settings.sky_color;
                 break;
@@ -272,6 +609,10 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
         pixel_color += color;
     }
 
+    // Accumulate
+    let previous = textureLoad(accumulation, vec2<i32>(in.position.xy), 0);
+    let sum = previous + vec4<f32>(pixel_color, f32(settings.samples));
+
     // Output
-    return vec4<f32>(pixel_color / f32(settings.samples), 1.0);
+    return FragmentOutput(vec4<f32>(sum.rgb / sum.a, 1.0), sum);
 }
@@ -1,3 +1,5 @@
+use std::num::NonZeroU32;
+
 use bevy::{
     asset::load_internal_asset,
     core_pipeline::{
@@ -8,29 +10,40 @@ use bevy::{
     render::{
         extract_component::{ComponentUniforms, ExtractComponentPlugin, UniformComponentPlugin},
         globals::{GlobalsBuffer, GlobalsUniform},
-        render_graph::{RenderGraphApp, RenderLabel, ViewNode, ViewNodeRunner},
+        graph::CameraDriverLabel,
+        render_asset::RenderAssets,
+        render_graph::{RenderGraph, RenderGraphApp, RenderLabel, ViewNode, ViewNodeRunner},
         render_resource::{
-            binding_types::uniform_buffer, BindGroupEntries, BindGroupLayout,
-            BindGroupLayoutEntries, BindingType, BufferBindingType, CachedRenderPipelineId,
-            ColorTargetState, ColorWrites, FragmentState, MultisampleState, Operations,
-            PipelineCache, PrimitiveState, RenderPassColorAttachment, RenderPassDescriptor,
-            RenderPipelineDescriptor, ShaderStages, ShaderType, StorageBuffer, TextureFormat,
+            binding_types::{sampler, texture_2d, uniform_buffer},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingResource,
+            BindingType, BufferBindingType, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            FragmentState, LoadOp, MultisampleState, Operations, PipelineCache, PrimitiveState,
+            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            SamplerBindingType, ShaderDefVal, ShaderStages, ShaderType, StoreOp, TextureDimension,
+            TextureSampleType,
         },
         renderer::RenderDevice,
+        settings::WgpuFeatures,
+        texture::{FallbackImage, GpuImage},
         view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
         Render, RenderApp, RenderSet,
     },
-    utils::HashMap,
 };
 
 use crate::{
-    data::{self, GpuMesh, GpuVertex, RayTraceMeta, RayTraceSettings, Texture},
-    extract,
+    accumulation::{self, RayTraceAccumulationTextures, ACCUMULATION_TEXTURE_FORMAT},
+    data::{
+        self, BvhNode, Emissive, GpuMesh, GpuVertex, Light, RayTraceAccumulation, RayTraceMeta,
+        RayTraceSettings, RayTraceTextureMode, Texture,
+    },
+    extract, skinning,
 };
 
 const RT_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(11955195141264208704);
 const QUERY_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(1234134802034481255);
 const MATH_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(12086621635481247250);
+const LIGHTS_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(5840185237216744396);
+const BSDF_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(9313830386519532611);
 
 pub struct RayTracePlugin;
 
@@ -39,9 +52,18 @@ impl Plugin for RayTracePlugin {
         load_internal_asset!(app, RT_SHADER_HANDLE, "raytrace.wgsl", Shader::from_wgsl);
         load_internal_asset!(app, QUERY_SHADER_HANDLE, "query.wgsl", Shader::from_wgsl);
         load_internal_asset!(app, MATH_SHADER_HANDLE, "math.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, LIGHTS_SHADER_HANDLE, "lights.wgsl", Shader::from_wgsl);
+        load_internal_asset!(app, BSDF_SHADER_HANDLE, "bsdf.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            skinning::SKINNING_SHADER_HANDLE,
+            "skinning.wgsl",
+            Shader::from_wgsl
+        );
 
         app.add_plugins((
             ExtractComponentPlugin::<RayTraceSettings>::default(),
+            ExtractComponentPlugin::<RayTraceAccumulation>::default(),
             UniformComponentPlugin::<RayTraceSettings>::default(),
         ));
 
@@ -49,36 +71,31 @@ impl Plugin for RayTracePlugin {
             return;
         };
 
-        render_app.insert_resource(RayTraceMeta {
-            objects: StorageBuffer::default(),
-            emissives: StorageBuffer::default(),
-
-            meshes: StorageBuffer::default(),
-            indices: StorageBuffer::default(),
-            vertices: StorageBuffer::default(),
-
-            handle_to_material: HashMap::new(),
-            handle_to_texture: HashMap::new(),
-            materials: StorageBuffer::default(),
-            textures: StorageBuffer::default(),
-            texture_data: StorageBuffer::default(),
-        });
+        render_app.init_resource::<RayTraceMeta>();
 
         render_app
             .add_systems(
                 ExtractSchedule,
                 (
                     (
-                        extract::extract_meshes,
+                        (extract::extract_meshes, skinning::extract_skins).chain(),
                         (extract::extract_textures, extract::extract_materials).chain(),
                     ),
                     extract::extract_visible,
+                    extract::extract_lights,
                 )
                     .chain(),
             )
             .add_systems(
                 Render,
-                extract::prepare_meshes.in_set(RenderSet::QueueMeshes),
+                (
+                    (
+                        (extract::prepare_meshes, skinning::prepare_skins).chain(),
+                        (extract::prepare_textures, extract::prepare_materials),
+                    )
+                        .in_set(RenderSet::QueueMeshes),
+                    accumulation::prepare_accumulation_textures.in_set(RenderSet::PrepareResources),
+                ),
             );
         render_app
             .add_render_graph_node::<ViewNodeRunner<RayTraceNode>>(Core3d, RayTraceLabel)
@@ -87,22 +104,81 @@ impl Plugin for RayTracePlugin {
                 (Node3d::EndMainPass, RayTraceLabel, Node3d::MotionBlur),
             );
 
-        render_app.insert_resource(extract::ProcessedMeshes {
-            meshes: Vec::new(),
-            asset_to_index: HashMap::new(),
-            changed: false,
-        });
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(skinning::SkinningLabel, skinning::SkinningNode);
+        render_graph.add_node_edge(skinning::SkinningLabel, CameraDriverLabel);
+
+        render_app.init_resource::<extract::TopLevelBvh>();
+        render_app.init_resource::<skinning::SkinnedMeshes>();
+        render_app.init_resource::<extract::ProcessedMeshes>();
     }
 
     fn finish(&self, app: &mut App) {
+        let requested_mode = app.world().get_resource::<RayTraceTextureMode>().copied();
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
         };
 
+        let supported = binding_arrays_are_usable(render_app.world().resource::<RenderDevice>());
+        let texture_mode = select_texture_mode(requested_mode, supported);
+
+        render_app.insert_resource(texture_mode);
         render_app.init_resource::<RayTracePipeline>();
+        render_app.init_resource::<skinning::SkinningPipeline>();
+        app.insert_resource(texture_mode);
     }
 }
 
+/// The inserted mode, unless it needs binding arrays the device doesn't support
+fn select_texture_mode(
+    requested_mode: Option<RayTraceTextureMode>,
+    supported: bool,
+) -> RayTraceTextureMode {
+    match requested_mode {
+        Some(RayTraceTextureMode::BindingArray) if !supported => {
+            warn!("Texture binding arrays aren't supported, falling back to a storage buffer");
+            RayTraceTextureMode::StorageBuffer
+        }
+        Some(mode) => mode,
+        None if supported => RayTraceTextureMode::BindingArray,
+        None => RayTraceTextureMode::StorageBuffer,
+    }
+}
+
+/// Upper bound on textures in the binding array, the rest of the device's limit is left to Bevy
+const MAX_TEXTURE_BINDINGS: u32 = 1024;
+
+/// Needs non-uniform indexing as neighbouring pixels hit different materials
+fn binding_arrays_are_usable(render_device: &RenderDevice) -> bool {
+    render_device.features().contains(
+        WgpuFeatures::TEXTURE_BINDING_ARRAY
+            | WgpuFeatures::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+    ) && texture_binding_array_size(render_device) >= 16
+}
+
+pub(crate) fn texture_binding_array_size(render_device: &RenderDevice) -> u32 {
+    let limits = render_device.limits();
+    // One sampled texture is taken by the accumulation texture
+    MAX_TEXTURE_BINDINGS
+        .min(
+            limits
+                .max_sampled_textures_per_shader_stage
+                .saturating_sub(1),
+        )
+        .min(limits.max_samplers_per_shader_stage)
+}
+
+/// Only filterable 2d images match the binding array's layout
+pub(crate) fn can_bind_in_array(render_device: &RenderDevice, image: &Image) -> bool {
+    let descriptor = &image.texture_descriptor;
+    descriptor.dimension == TextureDimension::D2
+        && descriptor.size.depth_or_array_layers == 1
+        && descriptor
+            .format
+            .sample_type(None, Some(render_device.features()))
+            == Some(TextureSampleType::Float { filterable: true })
+}
+
 // Shader
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
@@ -116,13 +192,14 @@ impl ViewNode for RayTraceNode {
         &'static ViewUniformOffset,
         &'static ViewTarget,
         &'static RayTraceSettings,
+        &'static RayTraceAccumulationTextures,
     );
 
     fn run<'w>(
         &self,
         _graph: &mut bevy::render::render_graph::RenderGraphContext,
         render_context: &mut bevy::render::renderer::RenderContext<'w>,
-        (view_uniform_offset, view_target, _settings): bevy::ecs::query::QueryItem<
+        (view_uniform_offset, view_target, _settings, accumulation): bevy::ecs::query::QueryItem<
             'w,
             Self::ViewQuery,
         >,
@@ -144,6 +221,24 @@ impl ViewNode for RayTraceNode {
             return Ok(());
         };
 
+        // Start accumulating from scratch
+        if accumulation.reset {
+            render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("ray_trace_accumulation_reset_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &accumulation.read.default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Default::default()),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        }
+
         let post_process = view_target.post_process_write();
         let bind_group_0 = {
             let view_uniforms_resource = world.resource::<ViewUniforms>();
@@ -151,7 +246,12 @@ impl ViewNode for RayTraceNode {
             render_context.render_device().create_bind_group(
                 "ray_trace_bind_group_0",
                 &ray_trace_pipeline.layout_0,
-                &BindGroupEntries::sequential((view_uniforms, globals_uniforms, settings_binding)),
+                &BindGroupEntries::sequential((
+                    view_uniforms,
+                    globals_uniforms,
+                    settings_binding,
+                    &accumulation.read.default_view,
+                )),
             )
         };
         let (bind_group_1, bind_group_meshes, bind_group_materials) = {
@@ -160,6 +260,46 @@ impl ViewNode for RayTraceNode {
                 return Ok(());
             };
 
+            // Padded with fallbacks up to the layout's size, images still loading use them too
+            let mut texture_views = Vec::new();
+            let mut samplers = Vec::new();
+            if let Some(size) = ray_trace_pipeline.texture_binding_array_size {
+                let images = world.resource::<RenderAssets<GpuImage>>();
+                let fallback = &world.resource::<FallbackImage>().d2;
+                for id in &meta.texture_images {
+                    let image = images.get(*id).unwrap_or(fallback);
+                    texture_views.push(&*image.texture_view);
+                    samplers.push(&*image.sampler);
+                }
+                texture_views.resize(size as usize, &*fallback.texture_view);
+                samplers.resize(size as usize, &*fallback.sampler);
+            }
+
+            let materials = (
+                meta.materials.binding().unwrap(),
+                meta.textures.binding().unwrap(),
+                meta.texture_data.binding().unwrap(),
+            );
+            let bind_group_materials = if texture_views.is_empty() {
+                render_context.render_device().create_bind_group(
+                    "ray_trace_bind_group_materials",
+                    &ray_trace_pipeline.layout_materials,
+                    &BindGroupEntries::sequential(materials),
+                )
+            } else {
+                render_context.render_device().create_bind_group(
+                    "ray_trace_bind_group_materials",
+                    &ray_trace_pipeline.layout_materials,
+                    &BindGroupEntries::sequential((
+                        materials.0,
+                        materials.1,
+                        materials.2,
+                        texture_views.as_slice(),
+                        BindingResource::SamplerArray(&samplers),
+                    )),
+                )
+            };
+
             (
                 render_context.render_device().create_bind_group(
                     "ray_trace_bind_group_1",
@@ -167,6 +307,10 @@ impl ViewNode for RayTraceNode {
                     &BindGroupEntries::sequential((
                         meta.objects.binding().unwrap(),
                         meta.emissives.binding().unwrap(),
+                        meta.tlas.binding().unwrap(),
+                        meta.lights.binding().unwrap(),
+                        meta.emissive_cdf.binding().unwrap(),
+                        meta.primitives.binding().unwrap(),
                     )),
                 ),
                 render_context.render_device().create_bind_group(
@@ -176,27 +320,27 @@ impl ViewNode for RayTraceNode {
                         meta.meshes.binding().unwrap(),
                         meta.indices.binding().unwrap(),
                         meta.vertices.binding().unwrap(),
+                        meta.nodes.binding().unwrap(),
                     )),
                 ),
-                render_context.render_device().create_bind_group(
-                    "ray_trace_bind_group_materials",
-                    &ray_trace_pipeline.layout_materials,
-                    &BindGroupEntries::sequential((
-                        meta.materials.binding().unwrap(),
-                        meta.textures.binding().unwrap(),
-                        meta.texture_data.binding().unwrap(),
-                    )),
-                ),
+                bind_group_materials,
             )
         };
 
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("ray_trace_pass"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: post_process.destination,
-                resolve_target: None,
-                ops: Operations::default(),
-            })],
+            color_attachments: &[
+                Some(RenderPassColorAttachment {
+                    view: post_process.destination,
+                    resolve_target: None,
+                    ops: Operations::default(),
+                }),
+                Some(RenderPassColorAttachment {
+                    view: &accumulation.write.default_view,
+                    resolve_target: None,
+                    ops: Operations::default(),
+                }),
+            ],
             depth_stencil_attachment: None,
             timestamp_writes: None,
             occlusion_query_set: None,
@@ -219,12 +363,19 @@ struct RayTracePipeline {
     layout_1: BindGroupLayout,
     layout_meshes: BindGroupLayout,
     layout_materials: BindGroupLayout,
+    /// Set with [`RayTraceTextureMode::BindingArray`]
+    texture_binding_array_size: Option<u32>,
     pipeline_id: CachedRenderPipelineId,
 }
 
 impl FromWorld for RayTracePipeline {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
+        let texture_binding_array_size = match world.resource::<RayTraceTextureMode>() {
+            RayTraceTextureMode::BindingArray => Some(texture_binding_array_size(render_device)),
+            RayTraceTextureMode::StorageBuffer => None,
+        };
+
         let layout_0 = render_device.create_bind_group_layout(
             "ray_trace_bind_group_layout_0",
             &BindGroupLayoutEntries::sequential(
@@ -233,6 +384,7 @@ impl FromWorld for RayTracePipeline {
                     uniform_buffer::<ViewUniform>(true),
                     uniform_buffer::<GlobalsUniform>(false),
                     uniform_buffer::<RayTraceSettings>(false),
+                    texture_2d(TextureSampleType::Float { filterable: false }),
                 ),
             ),
         );
@@ -249,57 +401,104 @@ impl FromWorld for RayTracePipeline {
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<u32>::min_size()),
+                        min_binding_size: Some(Vec::<Emissive>::min_size()),
                     },
-                ),
-            ),
-        );
-        let layout_meshes = render_device.create_bind_group_layout(
-            "ray_trace_bind_group_layout_meshes",
-            &BindGroupLayoutEntries::sequential(
-                ShaderStages::FRAGMENT,
-                (
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<GpuMesh>::min_size()),
+                        min_binding_size: Some(Vec::<BvhNode>::min_size()),
                     },
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<u32>::min_size()),
+                        min_binding_size: Some(Vec::<Light>::min_size()),
                     },
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<GpuVertex>::min_size()),
+                        min_binding_size: Some(Vec::<f32>::min_size()),
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(Vec::<data::Primitive>::min_size()),
                     },
                 ),
             ),
         );
-        let layout_materials = render_device.create_bind_group_layout(
-            "ray_trace_bind_group_layout_materials",
+        let layout_meshes = render_device.create_bind_group_layout(
+            "ray_trace_bind_group_layout_meshes",
             &BindGroupLayoutEntries::sequential(
                 ShaderStages::FRAGMENT,
                 (
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<data::Material>::min_size()),
+                        min_binding_size: Some(Vec::<GpuMesh>::min_size()),
                     },
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<Texture>::min_size()),
+                        min_binding_size: Some(Vec::<u32>::min_size()),
                     },
                     BindingType::Buffer {
                         ty: BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: Some(Vec::<f32>::min_size()),
+                        min_binding_size: Some(Vec::<GpuVertex>::min_size()),
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(Vec::<BvhNode>::min_size()),
                     },
                 ),
             ),
         );
+        let materials = (
+            BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: Some(Vec::<data::Material>::min_size()),
+            },
+            BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: Some(Vec::<Texture>::min_size()),
+            },
+            BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: Some(Vec::<u32>::min_size()),
+            },
+        );
+        let layout_materials = match texture_binding_array_size.and_then(NonZeroU32::new) {
+            Some(size) => render_device.create_bind_group_layout(
+                "ray_trace_bind_group_layout_materials",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        materials.0,
+                        materials.1,
+                        materials.2,
+                        texture_2d(TextureSampleType::Float { filterable: true }).count(size),
+                        sampler(SamplerBindingType::Filtering).count(size),
+                    ),
+                ),
+            ),
+            None => render_device.create_bind_group_layout(
+                "ray_trace_bind_group_layout_materials",
+                &BindGroupLayoutEntries::sequential(ShaderStages::FRAGMENT, materials),
+            ),
+        };
+
+        let mut shader_defs = Vec::new();
+        if let Some(size) = texture_binding_array_size {
+            shader_defs.push("TEXTURE_BINDING_ARRAY".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "TEXTURE_BINDING_ARRAY_SIZE".into(),
+                size,
+            ));
+        }
 
         let pipeline_id =
             world
@@ -315,18 +514,26 @@ impl FromWorld for RayTracePipeline {
                     vertex: fullscreen_shader_vertex_state(),
                     fragment: Some(FragmentState {
                         shader: RT_SHADER_HANDLE,
-                        shader_defs: vec![],
+                        shader_defs,
                         entry_point: "fragment".into(),
-                        targets: vec![Some(ColorTargetState {
-                            format: ViewTarget::TEXTURE_FORMAT_HDR, // TODO: support both HDR and SDR
-                            blend: None,
-                            write_mask: ColorWrites::ALL,
-                        })],
+                        targets: vec![
+                            Some(ColorTargetState {
+                                format: ViewTarget::TEXTURE_FORMAT_HDR, // TODO: support both HDR and SDR
+                                blend: None,
+                                write_mask: ColorWrites::ALL,
+                            }),
+                            Some(ColorTargetState {
+                                format: ACCUMULATION_TEXTURE_FORMAT,
+                                blend: None,
+                                write_mask: ColorWrites::ALL,
+                            }),
+                        ],
                     }),
                     primitive: PrimitiveState::default(),
                     depth_stencil: None,
                     multisample: MultisampleState::default(),
                     push_constant_ranges: vec![],
+                    zero_initialize_workgroup_memory: false,
                 });
 
         Self {
@@ -334,7 +541,43 @@ impl FromWorld for RayTracePipeline {
             layout_1,
             layout_meshes,
             layout_materials,
+            texture_binding_array_size,
             pipeline_id,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_texture_mode_is_kept() {
+        for supported in [false, true] {
+            assert_eq!(
+                select_texture_mode(Some(RayTraceTextureMode::StorageBuffer), supported),
+                RayTraceTextureMode::StorageBuffer
+            );
+        }
+        assert_eq!(
+            select_texture_mode(Some(RayTraceTextureMode::BindingArray), true),
+            RayTraceTextureMode::BindingArray
+        );
+    }
+
+    #[test]
+    fn texture_mode_falls_back_without_binding_arrays() {
+        assert_eq!(
+            select_texture_mode(Some(RayTraceTextureMode::BindingArray), false),
+            RayTraceTextureMode::StorageBuffer
+        );
+        assert_eq!(
+            select_texture_mode(None, false),
+            RayTraceTextureMode::StorageBuffer
+        );
+        assert_eq!(
+            select_texture_mode(None, true),
+            RayTraceTextureMode::BindingArray
+        );
+    }
+}
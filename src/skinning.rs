@@ -0,0 +1,438 @@
+use std::ops::Range;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{
+            morph::MeshMorphWeights,
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+        },
+        render_graph::{Node, NodeRunError, RenderGraphContext, RenderLabel},
+        render_resource::{
+            binding_types::{storage_buffer, storage_buffer_read_only},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId,
+            ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache, ShaderStages,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    bvh::Bounds,
+    data::{BvhNode, CpuMesh, GpuVertex, MorphDelta, RayTraceMeta, SkinInstance, SkinVertex},
+    extract::ProcessedMeshes,
+};
+
+pub const SKINNING_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(2784920370571658934);
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// A skinned or morphed object traced through its own copy of the mesh
+pub struct SkinnedInstance {
+    /// Slot of the bind pose mesh
+    pub mesh: usize,
+    /// Slot of the copy the joints and morph targets are applied to
+    pub slot: usize,
+    /// Empty when the object is only morphed
+    pub joints: Range<u32>,
+    pub weights: Range<u32>,
+    /// Bounds of the deformed vertices, in world space when skinned and mesh space otherwise
+    pub bounds: Bounds,
+}
+
+#[derive(Resource, Default)]
+pub struct SkinnedMeshes {
+    pub instances: HashMap<Entity, SkinnedInstance>,
+    /// Joint transforms multiplied with their inverse bind pose, skinned vertices end up in world space
+    pub joints: Vec<Mat4>,
+    /// One weight per morph target of every instance
+    pub weights: Vec<f32>,
+    /// Levels of the deepest hierarchy of any instance, each is refit by its own dispatch
+    pub max_depth: u32,
+}
+
+impl SkinnedMeshes {
+    /// Positions the skinning pass deforms the vertices of `mesh`, the bind pose of `instance`, to
+    pub fn deformed_positions(&self, instance: &SkinnedInstance, mesh: &CpuMesh) -> Vec<Vec3> {
+        let mut positions: Vec<Vec3> = mesh.vertices.iter().map(|v| v.position).collect();
+
+        // Morph targets are blended in bind pose before skinning
+        let weights = &self.weights[instance.weights.start as usize..instance.weights.end as usize];
+        let targets = mesh.morph_targets.chunks_exact(positions.len().max(1));
+        for (deltas, &weight) in targets.zip(weights).filter(|(_, &w)| w != 0.0) {
+            for (position, delta) in positions.iter_mut().zip(deltas) {
+                *position += delta.position * weight;
+            }
+        }
+
+        let joints = &self.joints[instance.joints.start as usize..instance.joints.end as usize];
+        if joints.is_empty() {
+            return positions;
+        }
+        for (position, skin) in positions.iter_mut().zip(&mesh.skin) {
+            let m = (0..4).fold(Mat4::ZERO, |m, i| {
+                let joint = (skin.joints[i] as usize).min(joints.len() - 1);
+                m + joints[joint] * skin.weights[i]
+            });
+            *position = m.transform_point3(*position);
+        }
+        positions
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn extract_skins(
+    query: Extract<
+        Query<
+            (
+                Entity,
+                &Mesh3d,
+                Option<&SkinnedMesh>,
+                Option<&MeshMorphWeights>,
+                &InheritedVisibility,
+            ),
+            Or<(With<SkinnedMesh>, With<MeshMorphWeights>)>,
+        >,
+    >,
+    joint_query: Extract<Query<&GlobalTransform>>,
+    inverse_bindposes: Extract<Res<Assets<SkinnedMeshInverseBindposes>>>,
+    mut processed_meshes: ResMut<ProcessedMeshes>,
+    mut skinned_meshes: ResMut<SkinnedMeshes>,
+) {
+    let mut instances = HashMap::new();
+    let mut joints = Vec::new();
+    let mut weights = Vec::new();
+    let mut max_depth = 0;
+
+    for (entity, mesh_handle, skin, morph_weights, visibility) in query.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let Some(&mesh) = processed_meshes.asset_to_index.get(&mesh_handle.id()) else {
+            continue;
+        };
+        let Some(cpu_mesh) = &processed_meshes.meshes[mesh] else {
+            continue;
+        };
+
+        let joint_head = joints.len();
+        if let Some(skin) = skin.filter(|_| !cpu_mesh.skin.is_empty()) {
+            if let Some(bindposes) = inverse_bindposes.get(&skin.inverse_bindposes) {
+                for (joint, bindpose) in skin.joints.iter().zip(bindposes.iter()) {
+                    let transform = joint_query
+                        .get(*joint)
+                        .map_or(Mat4::IDENTITY, GlobalTransform::compute_matrix);
+                    joints.push(transform * *bindpose);
+                }
+            }
+        }
+
+        // Missing weights leave their target out
+        let weight_head = weights.len();
+        if let Some(morph_weights) = morph_weights {
+            let morph_weights = morph_weights.weights();
+            weights.extend(
+                (0..cpu_mesh.morph_bounds.len())
+                    .map(|target| morph_weights.get(target).copied().unwrap_or(0.0)),
+            );
+        }
+
+        if joints.len() == joint_head && weights.len() == weight_head {
+            continue;
+        }
+
+        // Blending moves every vertex by at most the weighted extent of the targets
+        let (morph_min, morph_max) = cpu_mesh
+            .morph_bounds
+            .iter()
+            .zip(&weights[weight_head..])
+            .filter(|(bounds, _)| !bounds.is_empty())
+            .fold((Vec3::ZERO, Vec3::ZERO), |(lo, hi), (bounds, &w)| {
+                let (a, b) = (bounds.min * w, bounds.max * w);
+                (lo + a.min(b), hi + a.max(b))
+            });
+        let morphed = |bounds: &Bounds| Bounds {
+            min: bounds.min + morph_min,
+            max: bounds.max + morph_max,
+        };
+
+        // Every skinned vertex is a weighted average of the joints moving it, so it stays
+        // inside the union of their transformed bind pose bounds
+        let bounds = if joints.len() == joint_head {
+            morphed(&Bounds {
+                min: cpu_mesh.aabb_min,
+                max: cpu_mesh.aabb_max,
+            })
+        } else {
+            cpu_mesh
+                .joint_bounds
+                .iter()
+                .zip(&joints[joint_head..])
+                .filter(|(joint_bounds, _)| !joint_bounds.is_empty())
+                .fold(Bounds::EMPTY, |b, (joint_bounds, matrix)| {
+                    b.union(morphed(joint_bounds).transformed(matrix))
+                })
+        };
+
+        max_depth = max_depth.max(cpu_mesh.depth);
+
+        // The copy is made again when the bind pose mesh changed
+        let slot = match skinned_meshes.instances.remove(&entity) {
+            Some(instance) if instance.mesh == mesh && !processed_meshes.is_dirty(mesh) => {
+                instance.slot
+            }
+            previous => {
+                let mut copy = cpu_mesh.clone();
+                copy.skin = Vec::new();
+                copy.joint_bounds = Vec::new();
+                copy.morph_targets = Vec::new();
+                copy.morph_bounds = Vec::new();
+                if let Some(previous) = previous {
+                    processed_meshes.remove(previous.slot);
+                }
+                processed_meshes.insert(copy)
+            }
+        };
+
+        instances.insert(
+            entity,
+            SkinnedInstance {
+                mesh,
+                slot,
+                joints: joint_head as u32..joints.len() as u32,
+                weights: weight_head as u32..weights.len() as u32,
+                bounds,
+            },
+        );
+    }
+
+    // Despawned and hidden objects
+    for (_, instance) in skinned_meshes.instances.drain() {
+        processed_meshes.remove(instance.slot);
+    }
+
+    skinned_meshes.instances = instances;
+    skinned_meshes.joints = joints;
+    skinned_meshes.weights = weights;
+    skinned_meshes.max_depth = max_depth;
+}
+
+pub fn prepare_skins(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    processed_meshes: Res<ProcessedMeshes>,
+    skinned_meshes: Res<SkinnedMeshes>,
+    mut raytrace_meta: ResMut<RayTraceMeta>,
+) {
+    let allocation = |slot: usize| {
+        processed_meshes
+            .allocations
+            .get(slot)
+            .and_then(Option::as_ref)
+    };
+
+    let mut instances = Vec::new();
+    for instance in skinned_meshes.instances.values() {
+        let (Some(src), Some(dst)) = (allocation(instance.mesh), allocation(instance.slot)) else {
+            continue;
+        };
+
+        instances.push(SkinInstance {
+            src_vhead: src.vertices.start,
+            skin_head: src.skins.start,
+            vertex_count: src.vertices.end - src.vertices.start,
+            joint_head: instance.joints.start,
+            joint_count: instance.joints.end - instance.joints.start,
+            morph_head: src.morphs.start,
+            weight_head: instance.weights.start,
+            morph_target_count: instance.weights.end - instance.weights.start,
+            dst_vhead: dst.vertices.start,
+            ihead: dst.indices.start,
+            nhead: dst.nodes.start,
+            node_count: dst.nodes.end - dst.nodes.start,
+        });
+    }
+
+    if raytrace_meta.skin_instances.buffer().is_some()
+        && raytrace_meta.skin_instances.get() == &instances
+        && raytrace_meta.joints.get() == &skinned_meshes.joints
+        && raytrace_meta.morph_weights.get() == &skinned_meshes.weights
+    {
+        return;
+    }
+
+    *(raytrace_meta.skin_instances.get_mut()) = instances;
+    *(raytrace_meta.joints.get_mut()) = skinned_meshes.joints.clone();
+    *(raytrace_meta.morph_weights.get_mut()) = skinned_meshes.weights.clone();
+
+    raytrace_meta
+        .skin_instances
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta
+        .joints
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta
+        .morph_weights
+        .write_buffer(&render_device, &render_queue);
+    raytrace_meta.generation += 1;
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SkinningLabel;
+
+/// Skins every instance and refits its hierarchy before any view is traced
+#[derive(Default)]
+pub struct SkinningNode;
+
+impl Node for SkinningNode {
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let skinning_pipeline = world.resource::<SkinningPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let (Some(skin_pipeline), Some(refit_leaves_pipeline), Some(refit_nodes_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(skinning_pipeline.skin_id),
+            pipeline_cache.get_compute_pipeline(skinning_pipeline.refit_leaves_id),
+            pipeline_cache.get_compute_pipeline(skinning_pipeline.refit_nodes_id),
+        ) else {
+            return Ok(());
+        };
+
+        let meta = world.resource::<RayTraceMeta>();
+        let instances = meta.skin_instances.get();
+        if instances.is_empty() {
+            return Ok(());
+        }
+        let (
+            Some(instances_binding),
+            Some(joints),
+            Some(skins),
+            Some(indices),
+            Some(vertices),
+            Some(nodes),
+            Some(morphs),
+            Some(morph_weights),
+        ) = (
+            meta.skin_instances.binding(),
+            meta.joints.binding(),
+            meta.skins.binding(),
+            meta.indices.binding(),
+            meta.vertices.binding(),
+            meta.nodes.binding(),
+            meta.morphs.binding(),
+            meta.morph_weights.binding(),
+        )
+        else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "skinning_bind_group",
+            &skinning_pipeline.layout,
+            &BindGroupEntries::sequential((
+                instances_binding,
+                joints,
+                skins,
+                indices,
+                vertices,
+                nodes,
+                morphs,
+                morph_weights,
+            )),
+        );
+
+        let max_vertices = instances.iter().map(|i| i.vertex_count).max().unwrap_or(0);
+        let max_nodes = instances.iter().map(|i| i.node_count).max().unwrap_or(0);
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("skinning_pass"),
+                    timestamp_writes: None,
+                });
+        pass.set_bind_group(0, &bind_group, &[]);
+
+        pass.set_pipeline(skin_pipeline);
+        pass.dispatch_workgroups(
+            max_vertices.div_ceil(WORKGROUP_SIZE),
+            instances.len() as u32,
+            1,
+        );
+
+        // The leaves, then their parents one level further up with every dispatch
+        pass.set_pipeline(refit_leaves_pipeline);
+        pass.dispatch_workgroups(
+            max_nodes.div_ceil(WORKGROUP_SIZE),
+            instances.len() as u32,
+            1,
+        );
+        pass.set_pipeline(refit_nodes_pipeline);
+        for _ in 1..world.resource::<SkinnedMeshes>().max_depth {
+            pass.dispatch_workgroups(
+                max_nodes.div_ceil(WORKGROUP_SIZE),
+                instances.len() as u32,
+                1,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+pub struct SkinningPipeline {
+    layout: BindGroupLayout,
+    skin_id: CachedComputePipelineId,
+    refit_leaves_id: CachedComputePipelineId,
+    refit_nodes_id: CachedComputePipelineId,
+}
+
+impl FromWorld for SkinningPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            "skinning_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    storage_buffer_read_only::<Vec<SkinInstance>>(false),
+                    storage_buffer_read_only::<Vec<Mat4>>(false),
+                    storage_buffer_read_only::<Vec<SkinVertex>>(false),
+                    storage_buffer_read_only::<Vec<u32>>(false),
+                    storage_buffer::<Vec<GpuVertex>>(false),
+                    storage_buffer::<Vec<BvhNode>>(false),
+                    storage_buffer_read_only::<Vec<MorphDelta>>(false),
+                    storage_buffer_read_only::<Vec<f32>>(false),
+                ),
+            ),
+        );
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue = |entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("skinning_{entry_point}_pipeline").into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: vec![],
+                shader: SKINNING_SHADER_HANDLE,
+                shader_defs: vec![],
+                entry_point: entry_point.into(),
+                zero_initialize_workgroup_memory: false,
+            })
+        };
+        let skin_id = queue("skin");
+        let refit_leaves_id = queue("refit_leaves");
+        let refit_nodes_id = queue("refit_nodes");
+
+        Self {
+            layout,
+            skin_id,
+            refit_leaves_id,
+            refit_nodes_id,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+#import bevy_render::maths::inverse_mat3x3
+
+#import path_tracing::math::INFINITY
+
+// Bindings
+@group(0) @binding(0) var<storage> instances: array<SkinInstance>;
+@group(0) @binding(1) var<storage> joints: array<mat4x4<f32>>;
+@group(0) @binding(2) var<storage> skins: array<SkinVertex>;
+@group(0) @binding(3) var<storage> indices: array<u32>;
+@group(0) @binding(4) var<storage, read_write> vertices: array<Vertex>;
+@group(0) @binding(5) var<storage, read_write> nodes: array<BvhNode>;
+@group(0) @binding(6) var<storage> morphs: array<MorphDelta>;
+@group(0) @binding(7) var<storage> morph_weights: array<f32>;
+
+// Must match `query.wgsl`
+struct Vertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    uv: vec2<f32>,
+    tangent: vec4<f32>,
+}
+
+struct BvhNode {
+    aabb_min: vec3<f32>,
+    first: u32,
+    aabb_max: vec3<f32>,
+    count: u32,
+}
+
+struct SkinVertex {
+    joints: vec4<u32>,
+    weights: vec4<f32>,
+}
+
+struct MorphDelta {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    tangent: vec3<f32>,
+}
+
+struct SkinInstance {
+    src_vhead: u32,
+    skin_head: u32,
+    vertex_count: u32,
+
+    joint_head: u32,
+    joint_count: u32,
+
+    morph_head: u32,
+    weight_head: u32,
+    morph_target_count: u32,
+
+    dst_vhead: u32,
+    ihead: u32,
+    nhead: u32,
+    node_count: u32,
+}
+
+// One invocation per vertex, the instance is picked by the y workgroup
+@compute @workgroup_size(64)
+fn skin(@builtin(global_invocation_id) id: vec3<u32>) {
+    let instance = instances[id.y];
+    if id.x >= instance.vertex_count {
+        return;
+    }
+
+    var vertex = vertices[instance.src_vhead + id.x];
+
+    // Morph targets are blended in bind pose before skinning
+    for (var t = 0u; t < instance.morph_target_count; t++) {
+        let weight = morph_weights[instance.weight_head + t];
+        if weight == 0.0 {
+            continue;
+        }
+        let delta = morphs[instance.morph_head + t * instance.vertex_count + id.x];
+        vertex.position += delta.position * weight;
+        vertex.normal += delta.normal * weight;
+        vertex.tangent += vec4<f32>(delta.tangent * weight, 0.0);
+    }
+
+    if instance.joint_count == 0u {
+        vertex.normal = normalize(vertex.normal);
+        if any(vertex.tangent.xyz != vec3<f32>(0.0)) {
+            vertex.tangent = vec4<f32>(normalize(vertex.tangent.xyz), vertex.tangent.w);
+        }
+        vertices[instance.dst_vhead + id.x] = vertex;
+        return;
+    }
+
+    let skin = skins[instance.skin_head + id.x];
+    var m = mat4x4<f32>(vec4<f32>(0.0), vec4<f32>(0.0), vec4<f32>(0.0), vec4<f32>(0.0));
+    for (var i = 0u; i < 4u; i++) {
+        let joint = min(skin.joints[i], instance.joint_count - 1u);
+        m += joints[instance.joint_head + joint] * skin.weights[i];
+    }
+
+    vertex.position = (m * vec4<f32>(vertex.position, 1.0)).xyz;
+    let normal_matrix = transpose(inverse_mat3x3(mat3x3<f32>(m[0].xyz, m[1].xyz, m[2].xyz)));
+    vertex.normal = normalize(normal_matrix * vertex.normal);
+    if any(vertex.tangent.xyz != vec3<f32>(0.0)) {
+        vertex.tangent = vec4<f32>(normalize((m * vec4<f32>(vertex.tangent.xyz, 0.0)).xyz), vertex.tangent.w);
+    }
+    vertices[instance.dst_vhead + id.x] = vertex;
+}
+
+// Bounds of the leaves, one invocation per node with the instance picked by the y workgroup
+@compute @workgroup_size(64)
+fn refit_leaves(@builtin(global_invocation_id) id: vec3<u32>) {
+    let instance = instances[id.y];
+    if id.x >= instance.node_count {
+        return;
+    }
+    let node_index = instance.nhead + id.x;
+    let node = nodes[node_index];
+
+    // An empty mesh's root has no children and stays empty
+    if node.count == 0u && node.first != 0u {
+        return;
+    }
+
+    var aabb_min = vec3<f32>(INFINITY);
+    var aabb_max = vec3<f32>(-INFINITY);
+    for (var tri = node.first; tri < node.first + node.count; tri++) {
+        for (var v = 0u; v < 3u; v++) {
+            let index = indices[instance.ihead + tri * 3u + v];
+            let position = vertices[instance.dst_vhead + index].position;
+            aabb_min = min(aabb_min, position);
+            aabb_max = max(aabb_max, position);
+        }
+    }
+
+    nodes[node_index].aabb_min = aabb_min;
+    nodes[node_index].aabb_max = aabb_max;
+}
+
+// Bounds of the interior nodes from their children, dispatched once per level above the leaves.
+// After the n-th dispatch every node at most n levels above its leaves is final, nodes below
+// that are written again with the same bounds
+@compute @workgroup_size(64)
+fn refit_nodes(@builtin(global_invocation_id) id: vec3<u32>) {
+    let instance = instances[id.y];
+    if id.x >= instance.node_count {
+        return;
+    }
+    let node_index = instance.nhead + id.x;
+    let node = nodes[node_index];
+    if node.count > 0u || node.first == 0u {
+        return;
+    }
+
+    let left = nodes[instance.nhead + node.first];
+    let right = nodes[instance.nhead + node.first + 1u];
+    nodes[node_index].aabb_min = min(left.aabb_min, right.aabb_min);
+    nodes[node_index].aabb_max = max(left.aabb_max, right.aabb_max);
+}
@@ -0,0 +1,24 @@
+use bevy::math::Vec3;
+
+/// Xorshift, good enough for scattering test geometry and directions
+pub struct Rng(pub u64);
+
+impl Rng {
+    /// Uniform in `[0, 1)`
+    pub fn f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform in the unit cube
+    pub fn vec3(&mut self) -> Vec3 {
+        Vec3::new(self.f32(), self.f32(), self.f32())
+    }
+
+    /// Uniform in the cube from -1 to 1
+    pub fn signed_vec3(&mut self) -> Vec3 {
+        self.vec3() * 2.0 - 1.0
+    }
+}